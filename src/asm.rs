@@ -0,0 +1,827 @@
+use std::collections::BTreeMap;
+
+use crate::heap::Heap;
+use crate::instruction::{Instruction, Pointer};
+use crate::program::{Natives, Program, Signature};
+use crate::syscall;
+use crate::value::Value;
+
+// This module isn't really part of the VM, it's essentially
+// an extremely simplistic compiler. That's because the VM doesn't quite
+// support labels. It doesn't support named procedures either; it specifies
+// for procedures that contain an index into the Instruction list. Additionally,
+// this simplistic compiler allows for some basic comments.
+
+// A Label is a name and an instruction pointer
+type Label<'a> = (&'a str, Pointer);
+type Labels<'a> = BTreeMap<&'a str, Pointer>;
+
+// A procedure has a name, a start instruction pointer,
+// and an end instruction pointer.
+// The ending instruction pointer is just used to skip over
+// the procedure. It can also have a Signature.
+type Procedures<'a> = BTreeMap<&'a str, (Pointer, Pointer, Option<Signature>)>;
+
+// A constant is declared in the `.data` section. It has a name,
+// the pointer which PushAddr pushes for it, and its length.
+//
+// Example:
+//
+// .data
+//     greeting "Hello World\n"
+//     primes 2 3 5 7 11
+// .code
+//     PushAddr greeting
+//     PrintS
+type Constants<'a> = BTreeMap<&'a str, (Value, usize)>;
+
+// A Span points at a piece of the original source file. Lines and
+// columns start at 1, like they do in most editors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    pub len: usize,
+}
+
+// A Token is a single whitespace separated word of a line, along with
+// where it came from.
+#[derive(Debug, Clone, Copy)]
+struct Token<'a> {
+    text: &'a str,
+    span: Span,
+}
+
+// A SourceLine is a line of the file which actually contains an
+// instruction. Blank lines and comments are filtered out before
+// instructions are numbered, so each SourceLine remembers its
+// original text and line number for error messages.
+struct SourceLine<'a> {
+    text: &'a str,
+    tokens: Vec<Token<'a>>,
+}
+
+impl<'a> SourceLine<'a> {
+    fn words(&self) -> Vec<&'a str> {
+        self.tokens.iter().map(|t| t.text).collect()
+    }
+
+    // The span of the whole instruction, from the first token to the last.
+    fn span(&self) -> Span {
+        let first = self.tokens[0].span;
+        let last = self.tokens[self.tokens.len() - 1].span;
+        Span {
+            len: last.column + last.len - first.column,
+            ..first
+        }
+    }
+}
+
+// An AsmError is a problem found while assembling a file. It keeps the
+// offending source line so that it can be displayed on its own.
+#[derive(Debug)]
+pub struct AsmError {
+    pub file: String,
+    pub span: Span,
+    pub message: String,
+    pub source_line: String,
+}
+
+// AsmErrors are printed like rustc's diagnostics:
+//
+// error: unknown label `lop`
+//   --> test_files/sum.bytecode:22:9
+//    |
+// 22 |     JNE lop
+//    |         ^^^
+impl std::fmt::Display for AsmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let line_no = self.span.line.to_string();
+        let gutter = " ".repeat(line_no.len());
+
+        // Tabs are kept so that the caret lines up with the source line
+        let indent: String = self
+            .source_line
+            .chars()
+            .take(self.span.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        writeln!(f, "error: {}", self.message)?;
+        writeln!(
+            f,
+            "{}--> {}:{}:{}",
+            gutter, self.file, self.span.line, self.span.column
+        )?;
+        writeln!(f, "{} |", gutter)?;
+        writeln!(f, "{} | {}", line_no, self.source_line)?;
+        write!(
+            f,
+            "{} | {}{}",
+            gutter,
+            indent,
+            "^".repeat(self.span.len.max(1))
+        )
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+// The Assembler keeps track of the file being assembled and every error
+// found so far, so that all of them can be reported at once instead of
+// stopping at the first one.
+//
+// Usage:
+//
+// let program = Assembler::new("sum.bytecode").assemble(&source)?;
+pub struct Assembler {
+    file: String,
+    errors: Vec<AsmError>,
+    // The constant strings used by the program, and a Heap that
+    // they're allocated in so that their pointers are known before
+    // the program runs.
+    data: Vec<Vec<Value>>,
+    data_heap: Heap,
+    // The native functions declared so far, either by the host or by
+    // `.native` lines in the source
+    natives: Natives,
+}
+
+impl Assembler {
+    pub fn new(file: impl Into<String>) -> Self {
+        Assembler {
+            file: file.into(),
+            errors: Vec::new(),
+            data: Vec::new(),
+            data_heap: Heap::new(),
+            natives: Vec::new(),
+        }
+    }
+
+    // native declares a native function for every program this
+    // Assembler assembles, like a `.native` line at the top of each.
+    // Calling a function which is neither a procedure nor declared as
+    // a native is an error.
+    //
+    // let program = Assembler::new("sum.bytecode")
+    //     .native("sqrt", 1, 1)
+    //     .assemble(&source)?;
+    pub fn native(mut self, name: impl Into<String>, args: usize, results: usize) -> Self {
+        let name = name.into();
+        let signature = Signature { args, results };
+        match self.natives.iter_mut().find(|(n, _)| *n == name) {
+            Some(native) => native.1 = signature,
+            None => self.natives.push((name, signature)),
+        }
+        self
+    }
+
+    // assemble turns the source of a file into a Program,
+    // or every error found in it.
+    pub fn assemble(mut self, source: &str) -> Result<Program, Vec<AsmError>> {
+        let (data_lines, native_lines, lines) = split_sections(tokenize(source));
+        find_natives(&mut self, &native_lines);
+        let constants: Constants = find_constants(&mut self, &data_lines);
+        let labels: Labels = find_labels(&mut self, &lines);
+        let procedures: Procedures = find_procedures(&mut self, &lines);
+
+        let instructions: Vec<Instruction> = lines
+            .iter()
+            .enumerate()
+            .filter_map(|(ip, l)| {
+                parse_instruction(&mut self, ip, l, &labels, &procedures, &constants)
+            })
+            .collect();
+
+        if !self.errors.is_empty() {
+            self.errors.sort_by_key(|e| (e.span.line, e.span.column));
+            return Err(self.errors);
+        }
+
+        Ok(Program {
+            instructions,
+            labels: labels
+                .into_iter()
+                .map(|(name, ip)| (name.to_string(), ip))
+                .collect(),
+            procedures: procedures
+                .iter()
+                .map(|(name, &(start, end, _))| (name.to_string(), (start, end)))
+                .collect(),
+            signatures: procedures
+                .iter()
+                .filter_map(|(name, &(_, _, sig))| Some((name.to_string(), sig?)))
+                .collect(),
+            spans: lines.iter().map(|l| l.span()).collect(),
+            data: self.data,
+            constants: constants
+                .into_iter()
+                .map(|(name, (v, _))| (name.to_string(), v))
+                .collect(),
+            natives: self.natives,
+        })
+    }
+
+    // native_index returns the index of a declared native function in
+    // the Program's natives.
+    fn native_index(&self, name: &str) -> Option<usize> {
+        self.natives.iter().position(|(n, _)| n == name)
+    }
+
+    fn error(&mut self, line: &SourceLine, span: Span, message: String) {
+        self.errors.push(AsmError {
+            file: self.file.clone(),
+            span,
+            message,
+            source_line: line.text.to_string(),
+        });
+    }
+}
+
+// tokenize splits the source into SourceLines, dropping blank lines
+// and comments.
+fn tokenize(source: &str) -> Vec<SourceLine<'_>> {
+    source
+        .split('\n')
+        .enumerate()
+        .map(|(i, text)| {
+            let text = text.trim_end_matches('\r');
+            let mut tokens = Vec::new();
+            let mut start = None;
+
+            // Walk the line char by char so that columns are counted
+            // in characters rather than bytes. A trailing space is
+            // chained on to end the last token.
+            //
+            // A token that starts with a quote is a string literal, which
+            // runs until the closing quote even if it contains spaces.
+            let mut quoted = false;
+            let mut escaped = false;
+            let chars = text
+                .char_indices()
+                .chain(std::iter::once((text.len(), ' ')));
+            for (column, (byte_i, c)) in (1..).zip(chars) {
+                if quoted && byte_i < text.len() {
+                    match c {
+                        _ if escaped => escaped = false,
+                        '\\' => escaped = true,
+                        '"' => quoted = false,
+                        _ => {}
+                    }
+                    continue;
+                }
+
+                match (c.is_whitespace(), start) {
+                    (false, None) => {
+                        start = Some((byte_i, column));
+                        quoted = c == '"';
+                    }
+                    (true, Some((s, s_col))) => {
+                        tokens.push(Token {
+                            text: &text[s..byte_i],
+                            span: Span {
+                                line: i + 1,
+                                column: s_col,
+                                len: column - s_col,
+                            },
+                        });
+                        start = None;
+                    }
+                    _ => {}
+                }
+            }
+
+            SourceLine { text, tokens }
+        })
+        .filter(|l| !matches!(l.words().as_slice(), [] | ["--", ..]))
+        .collect()
+}
+
+// split_sections separates the lines of the `.data` section from the
+// lines of code. A file starts out in the code section, and `.data` and
+// `.code` switch between them, so the section markers themselves
+// aren't instructions. Neither are `.native` declarations, which can
+// go in either section.
+fn split_sections(lines: Vec<SourceLine>) -> (Vec<SourceLine>, Vec<SourceLine>, Vec<SourceLine>) {
+    let mut data = Vec::new();
+    let mut natives = Vec::new();
+    let mut code = Vec::new();
+    let mut in_data = false;
+
+    for line in lines.into_iter() {
+        match line.words().as_slice() {
+            [".data"] => in_data = true,
+            [".code"] => in_data = false,
+            [".native", ..] => natives.push(line),
+            _ if in_data => data.push(line),
+            _ => code.push(line),
+        }
+    }
+
+    (data, natives, code)
+}
+
+// find_natives parses the `.native` declarations, which name a native
+// function and give it a Signature. The same native can be declared
+// more than once, as long as it's declared the same way each time.
+fn find_natives(asm: &mut Assembler, lines: &[SourceLine]) {
+    for line in lines.iter() {
+        let (name, signature) = match line.words().as_slice() {
+            [_, name, signature @ ..] if !signature.is_empty() => {
+                match parse_signature(asm, line, signature) {
+                    Some(signature) => (name.to_string(), signature),
+                    None => continue,
+                }
+            }
+            _ => {
+                let message = "expected a native like `.native sqrt 1 -> 1`".to_string();
+                asm.error(line, line.span(), message);
+                continue;
+            }
+        };
+
+        match asm.native_index(&name) {
+            Some(i) if asm.natives[i].1 != signature => {
+                let message = format!("native `{}` is already declared differently", name);
+                asm.error(line, line.tokens[1].span, message);
+            }
+            Some(_) => {}
+            None => asm.natives.push((name, signature)),
+        }
+    }
+}
+
+// find_constants parses the `.data` section. Each line is a name
+// followed by either a string literal or a list of integers, which
+// get added to the data of the program.
+fn find_constants<'a>(asm: &mut Assembler, lines: &[SourceLine<'a>]) -> Constants<'a> {
+    let mut res = Constants::new();
+
+    for line in lines.iter() {
+        let name = line.tokens[0];
+        let values = &line.tokens[1..];
+
+        let block = match values {
+            [] => {
+                let message = format!("constant `{}` needs a string or integers", name.text);
+                asm.error(line, name.span, message);
+                continue;
+            }
+            [t] if t.text.starts_with('"') => parse_string(t.text)
+                .map_err(|message| asm.error(line, t.span, message))
+                .ok(),
+            // Every value is parsed before checking for errors, so that
+            // each invalid one gets reported.
+            _ => values
+                .iter()
+                .map(|t| {
+                    t.text.parse::<isize>().map(Value::Int).map_err(|_| {
+                        asm.error(line, t.span, format!("invalid integer `{}`", t.text))
+                    })
+                })
+                .collect::<Vec<_>>()
+                .into_iter()
+                .collect::<Result<Vec<_>, _>>()
+                .ok(),
+        };
+
+        // An invalid constant still gets added so that using it
+        // doesn't produce another error.
+        let block = match block {
+            Some(block) => block,
+            None => {
+                res.entry(name.text).or_insert((Value::Array(0), 0));
+                continue;
+            }
+        };
+
+        let p = asm.data_heap.alloc_values(&block);
+        let v = match values {
+            [t] if t.text.starts_with('"') => Value::Str(p),
+            _ => Value::Array(p),
+        };
+        let len = block.len();
+        asm.data.push(block);
+
+        if res.insert(name.text, (v, len)).is_some() {
+            let message = format!("constant `{}` is declared more than once", name.text);
+            asm.error(line, name.span, message);
+        }
+    }
+
+    res
+}
+
+// `parse_instruction` takes a line split by spaces and returns a singular
+// instruction that the line represents.
+//
+// Labels must be obtained by preprocessing the string, it's just a map of
+// names to their index in the instruction set.
+//
+// Procedures is similar, but it contains the instruction to jump to when called
+// along with the instruction to jump to in order to skip the Procedure declaration.
+// Procedures are encoded directly into the list of instructions, so the actual
+// Procedure declaration is replaced by a Jump instruction to skip over it.
+//
+// Example:
+//
+// Procedure proc_name -- Line n
+// ...
+// End -- line e
+//
+// Gets turned into
+//
+// Jump e -- Line n
+// ...
+// Noop -- line e
+//
+// Any problem with the line is recorded in the assembler, and None is returned.
+fn parse_instruction(
+    asm: &mut Assembler,
+    ip: Pointer,
+    line: &SourceLine,
+    labels: &Labels,
+    procedures: &Procedures,
+    constants: &Constants,
+) -> Option<Instruction> {
+    use Instruction::*;
+
+    // The argument of an instruction is always its second token
+    let arg = || line.tokens[1];
+
+    let value = |asm: &mut Assembler| -> Option<Value> {
+        let t = arg();
+        parse_value(asm, t.text)
+            .map_err(|message| asm.error(line, t.span, message))
+            .ok()
+    };
+
+    let index = |asm: &mut Assembler| -> Option<Pointer> {
+        let t = arg();
+        t.text
+            .parse::<Pointer>()
+            .map_err(|_| asm.error(line, t.span, format!("invalid stack index `{}`", t.text)))
+            .ok()
+    };
+
+    let label = |asm: &mut Assembler| -> Option<Pointer> {
+        let t = arg();
+        labels.get(t.text).copied().or_else(|| {
+            asm.error(line, t.span, format!("unknown label `{}`", t.text));
+            None
+        })
+    };
+
+    let procedure = |asm: &mut Assembler| -> Option<(Pointer, Pointer, Option<Signature>)> {
+        let t = arg();
+        procedures.get(t.text).copied().or_else(|| {
+            asm.error(line, t.span, format!("unknown procedure `{}`", t.text));
+            None
+        })
+    };
+
+    let constant = |asm: &mut Assembler| -> Option<(Value, usize)> {
+        let t = arg();
+        constants.get(t.text).copied().or_else(|| {
+            asm.error(line, t.span, format!("unknown constant `{}`", t.text));
+            None
+        })
+    };
+
+    // The procedure this line is in, if it's in one
+    let enclosing = procedures
+        .iter()
+        .find(|(_, &(start, end, _))| start < ip && ip < end);
+    let signature = enclosing.and_then(|(_, &(_, _, sig))| sig);
+
+    // An argument index has to be less than the declared arity
+    let arg_index = |asm: &mut Assembler| -> Option<Pointer> {
+        let i = index(asm)?;
+        match (enclosing, signature) {
+            (Some((name, _)), Some(sig)) if i >= sig.args => {
+                let message = format!(
+                    "`{}` only takes {} argument{}, so `{} {}` is out of range",
+                    name,
+                    sig.args,
+                    if sig.args == 1 { "" } else { "s" },
+                    line.tokens[0].text,
+                    i
+                );
+                asm.error(line, line.span(), message);
+                None
+            }
+            _ => Some(i),
+        }
+    };
+
+    let instruction = match line.words().as_slice() {
+        ["Push", _] => Push(value(asm)?),
+        ["Pop"] => Pop,
+        ["Add"] => Add,
+        ["Sub"] => Sub,
+        ["Mul"] => Mul,
+        ["Div"] => Div,
+        ["Incr"] => Incr,
+        ["Decr"] => Decr,
+        ["Jump", _] => Jump(label(asm)?),
+        ["JE", _] => JE(label(asm)?),
+        ["JNE", _] => JNE(label(asm)?),
+        ["JGE", _] => JGE(label(asm)?),
+        ["JLE", _] => JLE(label(asm)?),
+        ["JGT", _] => JGT(label(asm)?),
+        ["JLT", _] => JLT(label(asm)?),
+        ["Get", _] => Get(index(asm)?),
+        ["Set", _] => Set(index(asm)?),
+        ["SetPop", _] => SetPop(index(asm)?),
+        ["GetArg", _] => GetArg(arg_index(asm)?),
+        ["SetArg", _] => SetArg(arg_index(asm)?),
+        ["Print"] => Print,
+        ["PrintC"] => PrintC,
+        ["PrintStack"] => PrintStack,
+        // Malformed signatures are reported by find_procedures
+        ["Proc", _, ..] => Jump(procedure(asm)?.1),
+        // Calling a declared native function, rather than a procedure,
+        // calls whatever the host registers under that name at runtime
+        ["Call", name] => match (procedures.get(name), asm.native_index(name)) {
+            (Some(&(start, _, _)), _) => Call(start + 1),
+            (None, Some(i)) => CallNative(i),
+            (None, None) => Call(procedure(asm)?.0 + 1),
+        },
+        // A signed procedure drops its arguments when it returns, which
+        // a TailCall would skip
+        ["TailCall", _] => match (enclosing, signature) {
+            (Some((name, _)), Some(_)) => {
+                let message = format!(
+                    "`TailCall` can't be used in `{}`, since it has a signature",
+                    name
+                );
+                asm.error(line, line.tokens[0].span, message);
+                return None;
+            }
+            _ => TailCall(procedure(asm)?.0 + 1),
+        },
+        ["Ret"] => match signature {
+            Some(sig) => Return(sig.args, sig.results),
+            None => Ret,
+        },
+        ["Ret", _] => {
+            let keep = index(asm)?;
+            match (enclosing, signature) {
+                (Some((name, _)), Some(sig)) if keep != sig.results => {
+                    let message = format!(
+                        "`{}` returns {} value{}, so it can't `Ret {}`",
+                        name,
+                        sig.results,
+                        if sig.results == 1 { "" } else { "s" },
+                        keep
+                    );
+                    asm.error(line, line.span(), message);
+                    return None;
+                }
+                (_, Some(sig)) => RetN(sig.args, keep),
+                (_, None) => RetN(0, keep),
+            }
+        }
+        ["Enter", _] => Enter(index(asm)?),
+        ["Alloc"] => Alloc,
+        ["Free"] => Free,
+        ["Load"] => Load,
+        ["Store"] => Store,
+        ["PrintS"] => PrintS,
+        ["ReadInt"] => ReadInt,
+        ["ReadC"] => ReadC,
+        ["Halt"] => Halt,
+        ["Exit"] => Exit,
+        ["Syscall", _] => {
+            let n = index(asm)?;
+            if n >= syscall::TABLE.len() {
+                asm.error(line, arg().span, format!("there's no syscall {}", n));
+                return None;
+            }
+            Syscall(n)
+        }
+        ["PushAddr", _] => Push(constant(asm)?.0),
+        ["PushLen", _] => Push(Value::Int(constant(asm)?.1 as isize)),
+        ["label", _] | ["End"] | ["Noop"] => Noop,
+        [op, args @ ..] => {
+            let message = match expected_args(op) {
+                Some(counts) => format!(
+                    "`{}` takes {} argument{} but {} {} given",
+                    op,
+                    counts
+                        .iter()
+                        .map(|n| n.to_string())
+                        .collect::<Vec<_>>()
+                        .join(" or "),
+                    if counts == [1] { "" } else { "s" },
+                    args.len(),
+                    if args.len() == 1 { "was" } else { "were" }
+                ),
+                None => format!("unknown instruction `{}`", op),
+            };
+            let span = match expected_args(op) {
+                Some(_) => line.span(),
+                None => line.tokens[0].span,
+            };
+            asm.error(line, span, message);
+            return None;
+        }
+        [] => unreachable!("blank lines are filtered out by tokenize"),
+    };
+
+    Some(instruction)
+}
+
+// parse_value parses the argument of a Push. It can be an int, a float,
+// `true`, `false` or a string literal. Strings are added to the data
+// of the program, and the Value is a pointer to where they'll be in
+// the Heap once the program is loaded.
+//
+// Example: `3`, `-3.14`, `true`, `"Hello World\n"`
+fn parse_value(asm: &mut Assembler, text: &str) -> Result<Value, String> {
+    if let Ok(i) = text.parse::<isize>() {
+        return Ok(Value::Int(i));
+    }
+
+    match text {
+        "true" => return Ok(Value::Bool(true)),
+        "false" => return Ok(Value::Bool(false)),
+        _ => {}
+    }
+
+    if text.starts_with('"') {
+        let chars = parse_string(text)?;
+        let p = asm.data_heap.alloc_values(&chars);
+        asm.data.push(chars);
+        return Ok(Value::Str(p));
+    }
+
+    // Rust also parses `inf` and `NaN`, with or without a sign, and
+    // rounds literals like `1e999` up to infinity. None of them are
+    // literals here, so only finite floats are kept.
+    match text.parse::<f64>() {
+        Ok(x)
+            if x.is_finite()
+                && text.starts_with(|c: char| c.is_ascii_digit() || c == '-' || c == '.') =>
+        {
+            Ok(Value::Float(x))
+        }
+        _ => Err(format!("invalid value `{}`", text)),
+    }
+}
+
+// parse_string turns a quoted string literal into the char codes it
+// contains. The escapes `\n`, `\t`, `\\` and `\"` are supported.
+fn parse_string(text: &str) -> Result<Vec<Value>, String> {
+    let inner = match text.strip_prefix('"').and_then(|t| t.strip_suffix('"')) {
+        Some(inner) => inner,
+        None => return Err(format!("unterminated string `{}`", text)),
+    };
+
+    let mut res = Vec::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\\' => match chars.next() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some('\\') => '\\',
+                Some('"') => '"',
+                Some(c) => return Err(format!("unknown escape `\\{}`", c)),
+                None => return Err(format!("unterminated string `{}`", text)),
+            },
+            '"' => return Err(format!("unescaped quote in `{}`", text)),
+            c => c,
+        };
+        res.push(Value::Int(c as isize));
+    }
+
+    Ok(res)
+}
+
+// expected_args returns how many arguments a known instruction can
+// take, or None if the instruction doesn't exist. Ret takes an optional
+// count, and Proc an optional signature of three words.
+fn expected_args(op: &str) -> Option<&'static [usize]> {
+    match op {
+        "Pop" | "Add" | "Sub" | "Mul" | "Div" | "Incr" | "Decr" | "Print" | "PrintC"
+        | "PrintStack" | "End" | "Noop" | "Alloc" | "Free" | "Load" | "Store" | "PrintS"
+        | "ReadInt" | "ReadC" | "Halt" | "Exit" | ".data" | ".code" => Some(&[0]),
+        "Push" | "Jump" | "JE" | "JNE" | "JGE" | "JLE" | "JGT" | "JLT" | "Get" | "Set"
+        | "SetPop" | "GetArg" | "SetArg" | "Call" | "TailCall" | "Enter" | "Syscall" | "label"
+        | "PushAddr" | "PushLen" => Some(&[1]),
+        "Ret" => Some(&[0, 1]),
+        "Proc" => Some(&[1, 4]),
+        _ => None,
+    }
+}
+
+// find_labels takes the list of lines and returns the labels declared,
+// reporting any label that's declared twice.
+fn find_labels<'a>(asm: &mut Assembler, lines: &[SourceLine<'a>]) -> Labels<'a> {
+    let mut res = Labels::new();
+
+    for (i, line) in lines.iter().enumerate() {
+        if let Some((l, ip)) = find_label(i, line) {
+            if res.insert(l, ip).is_some() {
+                let t = line.tokens[1];
+                asm.error(
+                    line,
+                    t.span,
+                    format!("label `{}` is declared more than once", l),
+                );
+            }
+        }
+    }
+
+    res
+}
+
+// find_label takes a line and returns the label it represents,
+// or None if it does not represent a label.
+fn find_label<'a>(i: Pointer, line: &SourceLine<'a>) -> Option<Label<'a>> {
+    if let ["label", l] = line.words().as_slice() {
+        Some((l, i))
+    } else {
+        None
+    }
+}
+
+// find_procedures takes a list of lines and
+// returns the procedures declared.
+fn find_procedures<'a>(asm: &mut Assembler, lines: &[SourceLine<'a>]) -> Procedures<'a> {
+    let mut ip = 0;
+    let mut res = Procedures::new();
+
+    while ip < lines.len() {
+        if let ["Proc", proc_name, signature @ ..] = lines[ip].words().as_slice() {
+            let start_ip = ip;
+            while ip < lines.len() && lines[ip].words() != ["End"] {
+                ip += 1;
+            }
+
+            let name_span = lines[start_ip].tokens[1].span;
+            if ip == lines.len() {
+                let message = format!("procedure `{}` is missing an `End`", proc_name);
+                asm.error(&lines[start_ip], name_span, message);
+            }
+
+            let signature = parse_signature(asm, &lines[start_ip], signature);
+
+            // An unterminated procedure still gets added so that calling
+            // it doesn't produce another error.
+            if res
+                .insert(*proc_name, (start_ip, ip + 1, signature))
+                .is_some()
+            {
+                let message = format!("procedure `{}` is declared more than once", proc_name);
+                asm.error(&lines[start_ip], name_span, message);
+            }
+        } else {
+            ip += 1;
+        }
+    }
+
+    res
+}
+
+// parse_signature parses what follows the name of a procedure, which is
+// either nothing, or `<args> -> <results>`.
+fn parse_signature(asm: &mut Assembler, line: &SourceLine, words: &[&str]) -> Option<Signature> {
+    // A count has to fit in an isize too, since the verifier works out
+    // how a call changes the height of the stack from it
+    let count = |asm: &mut Assembler, i: usize| -> Option<usize> {
+        let t = line.tokens[i];
+        match t.text.parse::<usize>() {
+            Ok(n) if n <= isize::MAX as usize => Some(n),
+            Ok(_) => {
+                asm.error(line, t.span, format!("count `{}` is too large", t.text));
+                None
+            }
+            Err(_) => {
+                asm.error(line, t.span, format!("invalid count `{}`", t.text));
+                None
+            }
+        }
+    };
+
+    match words {
+        [] => None,
+        [_, "->", _] => {
+            let (args, results) = (count(asm, 2), count(asm, 4));
+            Some(Signature {
+                args: args?,
+                results: results?,
+            })
+        }
+        _ => {
+            let message = "expected a signature like `2 -> 1`".to_string();
+            let span = Span {
+                len: line.span().column + line.span().len - line.tokens[2].span.column,
+                ..line.tokens[2].span
+            };
+            asm.error(line, span, message);
+            None
+        }
+    }
+}
@@ -0,0 +1,439 @@
+use std::convert::TryFrom;
+
+use crate::instruction::{Instruction, Pointer};
+use crate::value::Value;
+
+// This module turns Instructions into actual bytecode and back.
+//
+// Each instruction is encoded as a one byte opcode followed by its
+// operand, if it has one. Operands are encoded as LEB128, a variable
+// length encoding which stores 7 bits per byte and uses the high bit
+// to say whether another byte follows. Small numbers, which are
+// by far the most common, only take a single byte.
+//
+// Example:
+//
+// Push 300 -- [PUSH, 0xac, 0x04]
+// Add      -- [ADD]
+// Jump 17  -- [JUMP, 0x11]
+//
+// Push immediates are signed, so they use signed LEB128. Jump targets
+// and stack indices can't be negative, so they use unsigned LEB128.
+// Pushing anything other than an int uses PUSH_VALUE, which is followed
+// by a tagged Value:
+//
+// Push 1.5  -- [PUSH_VALUE, FLOAT, <8 bytes of f64>]
+// Push true -- [PUSH_VALUE, BOOL, 0x01]
+//
+// Jump targets are still instruction indices rather than byte offsets,
+// since the VM runs off of decoded Instructions.
+
+pub mod op {
+    pub const PUSH: u8 = 0x00;
+    pub const POP: u8 = 0x01;
+    pub const ADD: u8 = 0x02;
+    pub const SUB: u8 = 0x03;
+    pub const INCR: u8 = 0x04;
+    pub const DECR: u8 = 0x05;
+    pub const MUL: u8 = 0x06;
+    pub const DIV: u8 = 0x07;
+    pub const JUMP: u8 = 0x08;
+    pub const JE: u8 = 0x09;
+    pub const JNE: u8 = 0x0a;
+    pub const JGT: u8 = 0x0b;
+    pub const JLT: u8 = 0x0c;
+    pub const JGE: u8 = 0x0d;
+    pub const JLE: u8 = 0x0e;
+    pub const GET: u8 = 0x0f;
+    pub const SET: u8 = 0x10;
+    pub const GET_ARG: u8 = 0x11;
+    pub const SET_ARG: u8 = 0x12;
+    pub const NOOP: u8 = 0x13;
+    pub const PRINT: u8 = 0x14;
+    pub const PRINT_C: u8 = 0x15;
+    pub const PRINT_STACK: u8 = 0x16;
+    pub const CALL: u8 = 0x17;
+    pub const RET: u8 = 0x18;
+    pub const ALLOC: u8 = 0x19;
+    pub const FREE: u8 = 0x1a;
+    pub const LOAD: u8 = 0x1b;
+    pub const STORE: u8 = 0x1c;
+    pub const PUSH_VALUE: u8 = 0x1d;
+    pub const PRINT_S: u8 = 0x1e;
+    pub const SET_POP: u8 = 0x1f;
+    pub const RETURN: u8 = 0x20;
+    pub const ENTER: u8 = 0x21;
+    pub const RET_N: u8 = 0x22;
+    pub const TAIL_CALL: u8 = 0x23;
+    pub const READ_INT: u8 = 0x24;
+    pub const READ_C: u8 = 0x25;
+    pub const CALL_NATIVE: u8 = 0x26;
+    pub const SYSCALL: u8 = 0x27;
+    pub const HALT: u8 = 0x28;
+    pub const EXIT: u8 = 0x29;
+}
+
+// The tags which start each encoded Value
+pub mod tag {
+    pub const INT: u8 = 0x00;
+    pub const FLOAT: u8 = 0x01;
+    pub const BOOL: u8 = 0x02;
+    pub const STR: u8 = 0x03;
+    pub const ARRAY: u8 = 0x04;
+}
+
+// The ways reading bytecode or an object file can fail. The offsets
+// are byte offsets into the data being read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    UnexpectedEof,
+    InvalidOpcode { opcode: u8, offset: usize },
+    // A LEB128 number didn't fit in an isize or usize
+    Overflow { offset: usize },
+    BadMagic,
+    UnsupportedVersion(u8),
+    InvalidUtf8 { offset: usize },
+    InvalidTag { tag: u8, offset: usize },
+    // A procedure in an object file's symbol table which doesn't have
+    // start < end <= the number of instructions
+    InvalidProcedure { offset: usize },
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::UnexpectedEof => write!(f, "unexpected end of bytecode"),
+            DecodeError::InvalidOpcode { opcode, offset } => {
+                write!(f, "invalid opcode {:#04x} at byte {}", opcode, offset)
+            }
+            DecodeError::Overflow { offset } => {
+                write!(f, "number at byte {} is too large", offset)
+            }
+            DecodeError::BadMagic => write!(f, "not a tinyvm object file"),
+            DecodeError::UnsupportedVersion(v) => {
+                write!(f, "unsupported object file version {}", v)
+            }
+            DecodeError::InvalidUtf8 { offset } => {
+                write!(f, "invalid UTF-8 in name at byte {}", offset)
+            }
+            DecodeError::InvalidTag { tag, offset } => {
+                write!(f, "invalid value tag {:#04x} at byte {}", tag, offset)
+            }
+            DecodeError::InvalidProcedure { offset } => {
+                write!(f, "procedure at byte {} is outside of the code", offset)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+pub fn write_uleb(out: &mut Vec<u8>, mut v: u64) {
+    loop {
+        let byte = (v & 0x7f) as u8;
+        v >>= 7;
+        if v == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+pub fn write_sleb(out: &mut Vec<u8>, mut v: i64) {
+    loop {
+        let byte = (v & 0x7f) as u8;
+        v >>= 7;
+
+        // The sign bit of the last byte has to match the sign of the number
+        let done = (v == 0 && byte & 0x40 == 0) || (v == -1 && byte & 0x40 != 0);
+        if done {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+// write_value writes a tag byte followed by the Value's payload.
+// Floats are stored as their 8 little endian bytes.
+pub fn write_value(out: &mut Vec<u8>, v: Value) {
+    match v {
+        Value::Int(i) => {
+            out.push(tag::INT);
+            write_sleb(out, i as i64);
+        }
+        Value::Float(x) => {
+            out.push(tag::FLOAT);
+            out.extend_from_slice(&x.to_le_bytes());
+        }
+        Value::Bool(b) => {
+            out.push(tag::BOOL);
+            out.push(b as u8);
+        }
+        Value::Str(p) => {
+            out.push(tag::STR);
+            write_sleb(out, p as i64);
+        }
+        Value::Array(p) => {
+            out.push(tag::ARRAY);
+            write_sleb(out, p as i64);
+        }
+    }
+}
+
+// A Reader walks over a slice of bytes, keeping track of the offset
+// for error messages.
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes, offset: 0 }
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.offset >= self.bytes.len()
+    }
+
+    pub fn byte(&mut self) -> Result<u8, DecodeError> {
+        let b = *self
+            .bytes
+            .get(self.offset)
+            .ok_or(DecodeError::UnexpectedEof)?;
+        self.offset += 1;
+        Ok(b)
+    }
+
+    pub fn bytes(&mut self, n: usize) -> Result<&'a [u8], DecodeError> {
+        let end = self
+            .offset
+            .checked_add(n)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or(DecodeError::UnexpectedEof)?;
+        let res = &self.bytes[self.offset..end];
+        self.offset = end;
+        Ok(res)
+    }
+
+    pub fn uleb(&mut self) -> Result<u64, DecodeError> {
+        let start = self.offset;
+        let mut res: u64 = 0;
+        let mut shift = 0;
+
+        loop {
+            let byte = self.byte()?;
+            let bits = u64::from(byte & 0x7f);
+            if shift >= 64 || (shift == 63 && bits > 1) {
+                return Err(DecodeError::Overflow { offset: start });
+            }
+            res |= bits << shift;
+            shift += 7;
+
+            if byte & 0x80 == 0 {
+                return Ok(res);
+            }
+        }
+    }
+
+    pub fn sleb(&mut self) -> Result<i64, DecodeError> {
+        let start = self.offset;
+        let mut res: i64 = 0;
+        let mut shift = 0;
+
+        loop {
+            let byte = self.byte()?;
+            if shift >= 64 {
+                return Err(DecodeError::Overflow { offset: start });
+            }
+            res |= i64::from(byte & 0x7f) << shift;
+            shift += 7;
+
+            if byte & 0x80 == 0 {
+                // Sign extend if the number is negative
+                if shift < 64 && byte & 0x40 != 0 {
+                    res |= -1 << shift;
+                }
+                return Ok(res);
+            }
+        }
+    }
+
+    pub fn usize(&mut self) -> Result<usize, DecodeError> {
+        let offset = self.offset;
+        usize::try_from(self.uleb()?).map_err(|_| DecodeError::Overflow { offset })
+    }
+
+    pub fn isize(&mut self) -> Result<isize, DecodeError> {
+        let offset = self.offset;
+        isize::try_from(self.sleb()?).map_err(|_| DecodeError::Overflow { offset })
+    }
+
+    pub fn value(&mut self) -> Result<Value, DecodeError> {
+        let offset = self.offset;
+        match self.byte()? {
+            tag::INT => Ok(Value::Int(self.isize()?)),
+            tag::FLOAT => {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(self.bytes(8)?);
+                Ok(Value::Float(f64::from_le_bytes(bytes)))
+            }
+            tag::BOOL => Ok(Value::Bool(self.byte()? != 0)),
+            tag::STR => Ok(Value::Str(self.isize()?)),
+            tag::ARRAY => Ok(Value::Array(self.isize()?)),
+            tag => Err(DecodeError::InvalidTag { tag, offset }),
+        }
+    }
+
+    pub fn str(&mut self) -> Result<&'a str, DecodeError> {
+        let len = self.usize()?;
+        let offset = self.offset;
+        std::str::from_utf8(self.bytes(len)?).map_err(|_| DecodeError::InvalidUtf8 { offset })
+    }
+}
+
+pub fn encode_instruction(out: &mut Vec<u8>, instruction: &Instruction) {
+    use Instruction::*;
+
+    let pointer = |out: &mut Vec<u8>, opcode: u8, p: Pointer| {
+        out.push(opcode);
+        write_uleb(out, p as u64);
+    };
+
+    match *instruction {
+        Push(Value::Int(d)) => {
+            out.push(op::PUSH);
+            write_sleb(out, d as i64);
+        }
+        Push(v) => {
+            out.push(op::PUSH_VALUE);
+            write_value(out, v);
+        }
+        Pop => out.push(op::POP),
+        Add => out.push(op::ADD),
+        Sub => out.push(op::SUB),
+        Incr => out.push(op::INCR),
+        Decr => out.push(op::DECR),
+        Mul => out.push(op::MUL),
+        Div => out.push(op::DIV),
+        Jump(p) => pointer(out, op::JUMP, p),
+        JE(p) => pointer(out, op::JE, p),
+        JNE(p) => pointer(out, op::JNE, p),
+        JGT(p) => pointer(out, op::JGT, p),
+        JLT(p) => pointer(out, op::JLT, p),
+        JGE(p) => pointer(out, op::JGE, p),
+        JLE(p) => pointer(out, op::JLE, p),
+        Get(i) => pointer(out, op::GET, i),
+        Set(i) => pointer(out, op::SET, i),
+        SetPop(i) => pointer(out, op::SET_POP, i),
+        GetArg(i) => pointer(out, op::GET_ARG, i),
+        SetArg(i) => pointer(out, op::SET_ARG, i),
+        Noop => out.push(op::NOOP),
+        Print => out.push(op::PRINT),
+        PrintC => out.push(op::PRINT_C),
+        PrintStack => out.push(op::PRINT_STACK),
+        Call(p) => pointer(out, op::CALL, p),
+        TailCall(p) => pointer(out, op::TAIL_CALL, p),
+        CallNative(n) => pointer(out, op::CALL_NATIVE, n),
+        Ret => out.push(op::RET),
+        Return(args, results) => {
+            out.push(op::RETURN);
+            write_uleb(out, args as u64);
+            write_uleb(out, results as u64);
+        }
+        Enter(n) => pointer(out, op::ENTER, n),
+        RetN(args, keep) => {
+            out.push(op::RET_N);
+            write_uleb(out, args as u64);
+            write_uleb(out, keep as u64);
+        }
+        Alloc => out.push(op::ALLOC),
+        Free => out.push(op::FREE),
+        Load => out.push(op::LOAD),
+        Store => out.push(op::STORE),
+        PrintS => out.push(op::PRINT_S),
+        ReadInt => out.push(op::READ_INT),
+        ReadC => out.push(op::READ_C),
+        Syscall(n) => pointer(out, op::SYSCALL, n),
+        Halt => out.push(op::HALT),
+        Exit => out.push(op::EXIT),
+    }
+}
+
+pub fn decode_instruction(r: &mut Reader) -> Result<Instruction, DecodeError> {
+    use Instruction::*;
+
+    let offset = r.offset();
+    let instruction = match r.byte()? {
+        op::PUSH => Push(Value::Int(r.isize()?)),
+        op::PUSH_VALUE => Push(r.value()?),
+        op::POP => Pop,
+        op::ADD => Add,
+        op::SUB => Sub,
+        op::INCR => Incr,
+        op::DECR => Decr,
+        op::MUL => Mul,
+        op::DIV => Div,
+        op::JUMP => Jump(r.usize()?),
+        op::JE => JE(r.usize()?),
+        op::JNE => JNE(r.usize()?),
+        op::JGT => JGT(r.usize()?),
+        op::JLT => JLT(r.usize()?),
+        op::JGE => JGE(r.usize()?),
+        op::JLE => JLE(r.usize()?),
+        op::GET => Get(r.usize()?),
+        op::SET => Set(r.usize()?),
+        op::SET_POP => SetPop(r.usize()?),
+        op::GET_ARG => GetArg(r.usize()?),
+        op::SET_ARG => SetArg(r.usize()?),
+        op::NOOP => Noop,
+        op::PRINT => Print,
+        op::PRINT_C => PrintC,
+        op::PRINT_STACK => PrintStack,
+        op::CALL => Call(r.usize()?),
+        op::TAIL_CALL => TailCall(r.usize()?),
+        op::CALL_NATIVE => CallNative(r.usize()?),
+        op::RET => Ret,
+        op::RETURN => Return(r.usize()?, r.usize()?),
+        op::ENTER => Enter(r.usize()?),
+        op::RET_N => RetN(r.usize()?, r.usize()?),
+        op::ALLOC => Alloc,
+        op::FREE => Free,
+        op::LOAD => Load,
+        op::STORE => Store,
+        op::PRINT_S => PrintS,
+        op::READ_INT => ReadInt,
+        op::READ_C => ReadC,
+        op::SYSCALL => Syscall(r.usize()?),
+        op::HALT => Halt,
+        op::EXIT => Exit,
+        opcode => return Err(DecodeError::InvalidOpcode { opcode, offset }),
+    };
+
+    Ok(instruction)
+}
+
+// encode turns a list of instructions into bytecode.
+pub fn encode(instructions: &[Instruction]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for instruction in instructions.iter() {
+        encode_instruction(&mut out, instruction);
+    }
+    out
+}
+
+// decode turns bytecode back into a list of instructions.
+pub fn decode(code: &[u8]) -> Result<Vec<Instruction>, DecodeError> {
+    let mut r = Reader::new(code);
+    let mut instructions = Vec::new();
+    while !r.is_empty() {
+        instructions.push(decode_instruction(&mut r)?);
+    }
+    Ok(instructions)
+}
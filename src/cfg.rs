@@ -0,0 +1,312 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+use crate::instruction::{Instruction, Pointer};
+use crate::program::Program;
+use crate::syscall;
+
+// This module splits a Program into basic blocks, and builds the
+// control flow graph between them and the call graph between its
+// procedures.
+//
+// A basic block is a run of instructions which always runs from start
+// to end: only its first instruction is jumped or returned to, and only
+// its last instruction jumps, calls or returns. The first instruction
+// of each block is called a leader.
+//
+// Example, from the factorial procedure in recursion.bytecode:
+//
+// b1    1..2       -> b2, b4 (taken)     JE retOne
+// b2    2..6       -> b3, b1 (call)      GetArg 0 .. Call factorial
+// b3    6..8       -> exit               Mul, Ret
+//
+// Both graphs can be written out for Graphviz with to_dot.
+
+// How control gets from one block to another
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EdgeKind {
+    // Running off the end of a block into the next one, which includes
+    // a conditional jump which isn't taken, and returning from a Call
+    Fallthrough,
+    // An unconditional Jump
+    Jump,
+    // A conditional jump which is taken
+    Branch,
+    // A Call, to the first block of the procedure
+    Call,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    pub start: Pointer,
+    // One past the last instruction of the block
+    pub end: Pointer,
+    pub successors: Vec<(usize, EdgeKind)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cfg {
+    pub blocks: Vec<Block>,
+}
+
+// leaders returns the first instruction of every basic block: the start
+// of the program, every jump and call target, and every instruction
+// after a jump, call or return.
+pub fn leaders(instructions: &[Instruction]) -> BTreeSet<Pointer> {
+    let mut res = BTreeSet::new();
+    if !instructions.is_empty() {
+        res.insert(0);
+    }
+
+    for (ip, instruction) in instructions.iter().enumerate() {
+        if let Some(target) = instruction.target() {
+            res.insert(target);
+        }
+        // Stopping the program ends a block like returning does
+        let returns = matches!(
+            instruction,
+            Instruction::Ret
+                | Instruction::Return(_, _)
+                | Instruction::RetN(_, _)
+                | Instruction::Halt
+                | Instruction::Exit
+                | Instruction::Syscall(syscall::EXIT)
+        );
+        if instruction.target().is_some() || returns {
+            res.insert(ip + 1);
+        }
+    }
+
+    // Jumps past the end just stop the program
+    res.split_off(&instructions.len());
+    res
+}
+
+impl Cfg {
+    pub fn new(instructions: &[Instruction]) -> Self {
+        use Instruction::*;
+
+        let starts: Vec<Pointer> = leaders(instructions).into_iter().collect();
+        let block_of = |ip: Pointer| starts.binary_search(&ip).ok();
+
+        let blocks = starts
+            .iter()
+            .enumerate()
+            .map(|(i, &start)| {
+                let end = starts.get(i + 1).copied().unwrap_or(instructions.len());
+                let next = block_of(end);
+
+                let mut successors = Vec::new();
+                let mut edge = |target: Option<usize>, kind| {
+                    if let Some(target) = target {
+                        successors.push((target, kind));
+                    }
+                };
+
+                match instructions[end - 1] {
+                    Jump(p) => edge(block_of(p), EdgeKind::Jump),
+                    JE(p) | JNE(p) | JGT(p) | JLT(p) | JGE(p) | JLE(p) => {
+                        edge(next, EdgeKind::Fallthrough);
+                        edge(block_of(p), EdgeKind::Branch);
+                    }
+                    Call(p) => {
+                        edge(next, EdgeKind::Fallthrough);
+                        edge(block_of(p), EdgeKind::Call);
+                    }
+                    // A TailCall never comes back, since the procedure
+                    // returns straight to whoever called this one
+                    TailCall(p) => edge(block_of(p), EdgeKind::Call),
+                    Ret | Return(_, _) | RetN(_, _) | Halt | Exit | Syscall(syscall::EXIT) => {}
+                    _ => edge(next, EdgeKind::Fallthrough),
+                }
+
+                Block {
+                    start,
+                    end,
+                    successors,
+                }
+            })
+            .collect();
+
+        Cfg { blocks }
+    }
+
+    // block_at returns the index of the block containing ip
+    pub fn block_at(&self, ip: Pointer) -> Option<usize> {
+        match self.blocks.binary_search_by_key(&ip, |b| b.start) {
+            Ok(i) => Some(i),
+            Err(0) => None,
+            Err(i) if ip < self.blocks[i - 1].end => Some(i - 1),
+            Err(_) => None,
+        }
+    }
+
+    // to_dot writes the graph for Graphviz. Each block lists its
+    // instructions, and the blocks of each procedure are grouped
+    // together in a box named after it.
+    pub fn to_dot(&self, program: &Program) -> String {
+        let names = block_names(program);
+        let mut out = String::new();
+
+        out.push_str("digraph cfg {\n");
+        out.push_str("    node [shape=box, fontname=\"monospace\"];\n");
+
+        let mut clusters: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+        for (i, block) in self.blocks.iter().enumerate() {
+            let proc = program.procedure_at(block.start).unwrap_or("main");
+            clusters.entry(proc).or_default().push(i);
+        }
+
+        for (proc, blocks) in clusters.iter() {
+            writeln!(out, "    subgraph \"cluster_{}\" {{", escape(proc)).unwrap();
+            writeln!(out, "        label=\"{}\";", escape(proc)).unwrap();
+            for &i in blocks.iter() {
+                let block = &self.blocks[i];
+
+                // Graphviz left aligns lines that end in \l
+                let mut label = String::new();
+                if let Some(name) = names.get(&block.start) {
+                    write!(label, "{}:\\l", escape(name)).unwrap();
+                }
+                for ip in block.start..block.end {
+                    let text = format!("{:>4}  {}", ip, program.instructions[ip]);
+                    write!(label, "{}\\l", escape(&text)).unwrap();
+                }
+                writeln!(out, "        b{} [label=\"{}\"];", i, label).unwrap();
+            }
+            out.push_str("    }\n");
+        }
+
+        for (i, block) in self.blocks.iter().enumerate() {
+            for &(target, kind) in block.successors.iter() {
+                let style = match kind {
+                    EdgeKind::Fallthrough => "",
+                    EdgeKind::Jump => " [label=\"jump\"]",
+                    EdgeKind::Branch => " [label=\"taken\"]",
+                    EdgeKind::Call => " [label=\"call\", style=dashed]",
+                };
+                writeln!(out, "    b{} -> b{}{};", i, target, style).unwrap();
+            }
+        }
+
+        out.push_str("}\n");
+        out
+    }
+}
+
+// The CFG can also be written as a plain listing, one block per line.
+//
+// Example: `b1    1..2       -> b2, b4 (taken)`
+impl std::fmt::Display for Cfg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, block) in self.blocks.iter().enumerate() {
+            let successors = block
+                .successors
+                .iter()
+                .map(|&(target, kind)| match kind {
+                    EdgeKind::Fallthrough => format!("b{}", target),
+                    EdgeKind::Jump => format!("b{} (jump)", target),
+                    EdgeKind::Branch => format!("b{} (taken)", target),
+                    EdgeKind::Call => format!("b{} (call)", target),
+                })
+                .collect::<Vec<_>>();
+
+            writeln!(
+                f,
+                "{:<5} {:<10} -> {}",
+                format!("b{}", i),
+                format!("{}..{}", block.start, block.end),
+                if successors.is_empty() {
+                    "exit".to_string()
+                } else {
+                    successors.join(", ")
+                }
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// A CallGraph has an edge from each procedure to every procedure it
+// calls, including native functions. Calls from outside of any
+// procedure come from `main`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CallGraph {
+    pub procedures: BTreeSet<String>,
+    pub calls: BTreeSet<(String, String)>,
+}
+
+impl CallGraph {
+    pub fn new(program: &Program) -> Self {
+        let mut graph = CallGraph::default();
+        graph.procedures.insert("main".to_string());
+        graph.procedures.extend(program.procedures.keys().cloned());
+
+        let callees: BTreeMap<Pointer, &str> = program
+            .procedures
+            .iter()
+            .map(|(name, &(start, _))| (start + 1, name.as_str()))
+            .collect();
+
+        for (ip, instruction) in program.instructions.iter().enumerate() {
+            let callee = match *instruction {
+                Instruction::Call(p) | Instruction::TailCall(p) => match callees.get(&p) {
+                    Some(name) => name.to_string(),
+                    None => format!("ip_{}", p),
+                },
+                Instruction::CallNative(i) => match program.natives.get(i) {
+                    Some((name, _)) => name.to_string(),
+                    None => format!("native_{}", i),
+                },
+                _ => continue,
+            };
+            let caller = program.procedure_at(ip).unwrap_or("main");
+            graph.procedures.insert(callee.clone());
+            graph.calls.insert((caller.to_string(), callee));
+        }
+
+        graph
+    }
+
+    pub fn to_dot(&self) -> String {
+        let mut out = String::new();
+        out.push_str("digraph calls {\n");
+        for name in self.procedures.iter() {
+            writeln!(out, "    \"{}\";", escape(name)).unwrap();
+        }
+        for (caller, callee) in self.calls.iter() {
+            writeln!(out, "    \"{}\" -> \"{}\";", escape(caller), escape(callee)).unwrap();
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+// Example: `main -> factorial`
+impl std::fmt::Display for CallGraph {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (caller, callee) in self.calls.iter() {
+            writeln!(f, "{} -> {}", caller, callee)?;
+        }
+        Ok(())
+    }
+}
+
+// block_names returns the label or procedure name for instructions
+// which have one. Procedures are named at their first instruction,
+// since that's where calls go.
+fn block_names(program: &Program) -> BTreeMap<Pointer, &str> {
+    let mut res: BTreeMap<Pointer, &str> = program
+        .labels
+        .iter()
+        .map(|(name, &ip)| (ip, name.as_str()))
+        .collect();
+    for (name, &(start, _)) in program.procedures.iter() {
+        res.insert(start + 1, name.as_str());
+    }
+    res
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
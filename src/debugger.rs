@@ -0,0 +1,244 @@
+use std::collections::BTreeSet;
+use std::io::{BufRead, Write};
+
+use crate::instruction::Pointer;
+use crate::vm::{Vm, VmError};
+
+// An interactive, gdb-style debugger which drives a Vm one step
+// at a time.
+//
+// Commands:
+//
+// step, s              run a single instruction
+// next, n              like step, but runs a whole Call at once
+// finish, f            run until the current procedure returns
+// continue, c          run until a breakpoint or the end of the program
+// break, b <where>     set a breakpoint on a label, a procedure, or a source line
+// delete, d <where>    remove a breakpoint
+// stack, p             print the stack, split at the current StackFrame
+// backtrace, bt        print the CallStack
+// where, w             print the current instruction
+// help, h              print the list of commands
+// quit, q              stop debugging
+pub struct Debugger {
+    vm: Vm,
+    source: Option<String>,
+    breakpoints: BTreeSet<Pointer>,
+    state: State,
+}
+
+// Whether the program can still be run
+#[derive(Debug)]
+enum State {
+    Running,
+    Finished,
+    Faulted(VmError),
+}
+
+// Why a run of the program stopped
+enum Stop {
+    Step,
+    Breakpoint,
+    Finished,
+    Faulted,
+}
+
+const HELP: &str = "commands:
+    step, s              run a single instruction
+    next, n              like step, but runs a whole Call at once
+    finish, f            run until the current procedure returns
+    continue, c          run until a breakpoint or the end of the program
+    break, b <where>     set a breakpoint on a label, a procedure, or a source line
+    delete, d <where>    remove a breakpoint
+    stack, p             print the stack, split at the current stack frame
+    backtrace, bt        print the call stack
+    where, w             print the current instruction
+    help, h              print this message
+    quit, q              stop debugging";
+
+impl Debugger {
+    // source is the text the Program was assembled from, if there is
+    // one. It's only used to show the current line.
+    pub fn new(vm: Vm, source: Option<String>) -> Self {
+        Debugger {
+            vm,
+            source,
+            breakpoints: BTreeSet::new(),
+            state: State::Running,
+        }
+    }
+
+    pub fn vm(&self) -> &Vm {
+        &self.vm
+    }
+
+    // run reads commands from input until it runs out or gets `quit`.
+    pub fn run(&mut self, input: impl BufRead, out: &mut impl Write) -> std::io::Result<()> {
+        self.print_location(out)?;
+        write!(out, "(tvdb) ")?;
+        out.flush()?;
+
+        for line in input.lines() {
+            let line = line?;
+            let words = line.split_whitespace().collect::<Vec<_>>();
+
+            match words.as_slice() {
+                [] => {}
+                ["quit"] | ["q"] => return Ok(()),
+                ["help"] | ["h"] => writeln!(out, "{}", HELP)?,
+                ["step"] | ["s"] => self.resume(out, |_| true)?,
+                ["next"] | ["n"] => {
+                    let depth = self.vm.call_stack().len();
+                    self.resume(out, move |vm| vm.call_stack().len() <= depth)?
+                }
+                ["finish"] | ["f"] => {
+                    let depth = self.vm.call_stack().len();
+                    if depth == 0 {
+                        writeln!(out, "not inside of a procedure")?;
+                    } else {
+                        self.resume(out, move |vm| vm.call_stack().len() < depth)?
+                    }
+                }
+                ["continue"] | ["c"] => self.resume(out, |_| false)?,
+                ["break", place] | ["b", place] => match self.resolve(place) {
+                    Some(ip) => {
+                        self.breakpoints.insert(ip);
+                        writeln!(out, "breakpoint set at {}", self.describe(ip))?;
+                    }
+                    None => writeln!(out, "no label, procedure or line `{}`", place)?,
+                },
+                ["delete", place] | ["d", place] => match self.resolve(place) {
+                    Some(ip) if self.breakpoints.remove(&ip) => {
+                        writeln!(out, "breakpoint removed at {}", self.describe(ip))?
+                    }
+                    _ => writeln!(out, "no breakpoint at `{}`", place)?,
+                },
+                ["stack"] | ["p"] => writeln!(out, "{}", self.vm.stack_string())?,
+                ["backtrace"] | ["bt"] => self.print_backtrace(out)?,
+                ["where"] | ["w"] => self.print_location(out)?,
+                _ => writeln!(out, "unknown command `{}`, try `help`", line.trim())?,
+            }
+
+            write!(out, "(tvdb) ")?;
+            out.flush()?;
+        }
+
+        Ok(())
+    }
+
+    // resume runs the program until `done` says to stop, a breakpoint
+    // is hit, or the program ends. `done` is checked after every
+    // instruction.
+    fn resume(&mut self, out: &mut impl Write, done: impl Fn(&Vm) -> bool) -> std::io::Result<()> {
+        if !matches!(self.state, State::Running) {
+            return self.print_state(out);
+        }
+
+        let stop = loop {
+            match self.vm.step() {
+                Ok(true) => {}
+                Ok(false) => break Stop::Finished,
+                Err(e) => {
+                    self.state = State::Faulted(e);
+                    break Stop::Faulted;
+                }
+            }
+
+            if self.vm.is_finished() {
+                break Stop::Finished;
+            }
+            if done(&self.vm) {
+                break Stop::Step;
+            }
+            if self.breakpoints.contains(&self.vm.pointer()) {
+                break Stop::Breakpoint;
+            }
+        };
+
+        // The program's own output isn't newline terminated,
+        // so make sure it's visible before printing anything else.
+        self.vm.flush()?;
+
+        match stop {
+            Stop::Step => self.print_location(out),
+            Stop::Breakpoint => {
+                writeln!(out, "breakpoint hit")?;
+                self.print_location(out)
+            }
+            Stop::Finished => {
+                self.state = State::Finished;
+                self.print_state(out)
+            }
+            Stop::Faulted => self.print_state(out),
+        }
+    }
+
+    fn print_state(&self, out: &mut impl Write) -> std::io::Result<()> {
+        match &self.state {
+            State::Running => Ok(()),
+            State::Finished => writeln!(out, "program finished, stack: {}", self.vm.stack_string()),
+            State::Faulted(e) => writeln!(out, "program faulted: {}", e),
+        }
+    }
+
+    // resolve turns a breakpoint location into an instruction pointer.
+    // Procedures break on the first instruction of their body.
+    fn resolve(&self, place: &str) -> Option<Pointer> {
+        let program = self.vm.program();
+
+        if let Ok(line) = place.parse::<usize>() {
+            return program.ip_at_line(line);
+        }
+
+        program
+            .procedures
+            .get(place)
+            .map(|&(start, _)| start + 1)
+            .or_else(|| program.labels.get(place).copied())
+    }
+
+    // describe returns something like `ip 12, line 20, in fib`
+    fn describe(&self, ip: Pointer) -> String {
+        let program = self.vm.program();
+        let mut res = format!("ip {}", ip);
+        if let Some(span) = program.span(ip) {
+            res += &format!(", line {}", span.line);
+        }
+        if let Some(name) = program.procedure_at(ip) {
+            res += &format!(", in {}", name);
+        }
+        res
+    }
+
+    fn print_location(&self, out: &mut impl Write) -> std::io::Result<()> {
+        let ip = self.vm.pointer();
+        let program = self.vm.program();
+
+        let instruction = match program.instructions.get(ip) {
+            Some(instruction) => instruction,
+            None => return self.print_state(out),
+        };
+
+        let source_line = program.span(ip).and_then(|span| {
+            self.source
+                .as_ref()
+                .and_then(|s| s.lines().nth(span.line - 1))
+                .map(str::trim)
+        });
+
+        match source_line {
+            Some(text) => writeln!(out, "{}: {}", self.describe(ip), text),
+            None => writeln!(out, "{}: {}", self.describe(ip), instruction),
+        }
+    }
+
+    // Each StackFrame saves the instruction after its Call,
+    // so the caller is found by looking one instruction back.
+    fn print_backtrace(&self, out: &mut impl Write) -> std::io::Result<()> {
+        writeln!(out, "#0 {}", self.describe(self.vm.pointer()))?;
+        for (i, frame) in self.vm.call_stack().iter().rev().enumerate() {
+            writeln!(out, "#{} {}", i + 1, self.describe(frame.ip - 1))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,396 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
+
+use crate::instruction::{Instruction, Pointer};
+use crate::program::{Program, Signature};
+use crate::value::Value;
+
+// The disassembler turns a Program back into assembly source.
+//
+// Since labels and procedures are compiled into Noops and Jumps,
+// every line of the source is still an instruction, so the
+// disassembler only has to decide how to write each one:
+//
+// Jump e    ->  Proc name     (if it's the start of a procedure)
+// Noop      ->  End           (if it's the end of a procedure)
+// Noop      ->  label name    (if something jumps to it)
+// Call p    ->  Call name      (likewise TailCall)
+// CallNative i -> Call name   (named from the Program's natives)
+// Jump l    ->  Jump name
+// Push str  ->  Push "text"  (if it points at the Program's data)
+// Push ptr  ->  PushAddr name (if it points at a constant)
+// Return    ->  Ret           (in a procedure with a signature)
+// RetN _ n  ->  Ret n
+//
+// Constants are written out in a `.data` section at the top, after a
+// `.native` line for each native function.
+// Names come from the Program's symbol table when it has one. When it
+// doesn't, procedures are named after their instruction pointer, like
+// `P17`, labels likewise, like `L17`, and constants after their place
+// in the data, like `D3`.
+//
+// Because every instruction maps back to exactly one line, assembling
+// the output gives back the same instructions.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DisasmError {
+    // A jump which doesn't point at a Noop, so no label can be placed there
+    BadJumpTarget { ip: Pointer, target: Pointer },
+    // A call which doesn't point right after a procedure header
+    BadCallTarget { ip: Pointer, target: Pointer },
+    // A pushed string or array which isn't one of the Program's data blocks
+    BadPointer { ip: Pointer, pointer: isize },
+    // A CallNative whose index isn't in the Program's natives
+    BadNative { ip: Pointer, index: usize },
+    // A data block which can't be written as a string or integers
+    BadData { index: usize },
+}
+
+impl std::fmt::Display for DisasmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DisasmError::BadJumpTarget { ip, target } => write!(
+                f,
+                "instruction {} jumps to {}, which can't be written as a label",
+                ip, target
+            ),
+            DisasmError::BadCallTarget { ip, target } => write!(
+                f,
+                "instruction {} calls {}, which isn't the start of a procedure",
+                ip, target
+            ),
+            DisasmError::BadPointer { ip, pointer } => write!(
+                f,
+                "instruction {} pushes a pointer to {}, which isn't part of the program's data",
+                ip, pointer
+            ),
+            DisasmError::BadNative { ip, index } => write!(
+                f,
+                "instruction {} calls native {}, which the program doesn't name",
+                ip, index
+            ),
+            DisasmError::BadData { index } => write!(
+                f,
+                "data block {} can't be written as a string or integers",
+                index
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DisasmError {}
+
+// What a line of the output looks like, if it's not just the instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Line {
+    Plain,
+    Proc(String),
+    End,
+    Label(String),
+}
+
+// fresh returns `base`, or `base` with a suffix if that name is taken.
+fn fresh(taken: &mut BTreeSet<String>, base: String) -> String {
+    let mut name = base.clone();
+    let mut i = 2;
+    while taken.contains(&name) {
+        name = format!("{}_{}", base, i);
+        i += 1;
+    }
+    taken.insert(name.clone());
+    name
+}
+
+// is_proc checks whether a procedure could start at `start`, which
+// means its header is a Jump over the body to right after an End Noop.
+fn is_proc(instructions: &[Instruction], lines: &[Line], start: Pointer) -> Option<Pointer> {
+    match instructions.get(start) {
+        Some(&Instruction::Jump(end))
+            if end > start + 1
+                && end <= instructions.len()
+                && instructions[end - 1] == Instruction::Noop
+                && lines[start] == Line::Plain
+                && lines[end - 1] == Line::Plain =>
+        {
+            Some(end)
+        }
+        _ => None,
+    }
+}
+
+pub fn disassemble(program: &Program) -> Result<String, DisasmError> {
+    let instructions = &program.instructions;
+    let mut lines = vec![Line::Plain; instructions.len()];
+
+    // Procedures take priority over natives, so made-up procedure names
+    // have to steer clear of them
+    let mut proc_names: BTreeSet<String> = program.procedures.keys().cloned().collect();
+    proc_names.extend(program.natives.iter().map(|(name, _)| name.clone()));
+    let mut label_names: BTreeSet<String> = program.labels.keys().cloned().collect();
+
+    // Start off with the symbol table, skipping any entries which
+    // don't match the code.
+    for (name, &(start, end)) in program.procedures.iter() {
+        if is_proc(instructions, &lines, start) == Some(end) {
+            lines[start] = Line::Proc(name.clone());
+            lines[end - 1] = Line::End;
+        }
+    }
+
+    for (name, &ip) in program.labels.iter() {
+        if instructions.get(ip) == Some(&Instruction::Noop) && lines[ip] == Line::Plain {
+            lines[ip] = Line::Label(name.clone());
+        }
+    }
+
+    // Then make up names for anything that's called or jumped to
+    // without one.
+    for (ip, instruction) in instructions.iter().enumerate() {
+        if let Instruction::Call(target) | Instruction::TailCall(target) = *instruction {
+            let start = target.wrapping_sub(1);
+            if matches!(lines.get(start), Some(Line::Proc(_))) {
+                continue;
+            }
+
+            match is_proc(instructions, &lines, start) {
+                Some(end) => {
+                    lines[start] = Line::Proc(fresh(&mut proc_names, format!("P{}", start)));
+                    lines[end - 1] = Line::End;
+                }
+                None => return Err(DisasmError::BadCallTarget { ip, target }),
+            }
+        }
+    }
+
+    for (ip, instruction) in instructions.iter().enumerate() {
+        let target = match instruction {
+            Instruction::Call(_) | Instruction::TailCall(_) => continue,
+            _ if matches!(lines[ip], Line::Proc(_)) => continue,
+            _ => match instruction.target() {
+                Some(target) => target,
+                None => continue,
+            },
+        };
+
+        match (instructions.get(target), lines.get(target)) {
+            (_, Some(Line::Label(_))) => {}
+            (Some(Instruction::Noop), Some(Line::Plain)) => {
+                lines[target] = Line::Label(fresh(&mut label_names, format!("L{}", target)));
+            }
+            // A procedure which is never called still has a header
+            // jumping over it.
+            _ if is_proc(instructions, &lines, ip) == Some(target) => {
+                lines[ip] = Line::Proc(fresh(&mut proc_names, format!("P{}", ip)));
+                lines[target - 1] = Line::End;
+            }
+            _ => return Err(DisasmError::BadJumpTarget { ip, target }),
+        }
+    }
+
+    let name_of = |target: Pointer| match lines.get(target) {
+        Some(Line::Proc(name)) | Some(Line::Label(name)) => name.as_str(),
+        _ => unreachable!("every target was named above"),
+    };
+
+    // Strings and arrays are pushed as pointers into the data, which
+    // the assembler lays out one block after another: first the `.data`
+    // section, then each string literal as it's pushed. So the last
+    // blocks can go back to being literals if they're pushed once each,
+    // in order, and every block before them is written in the `.data`
+    // section to keep its place, with a made-up name if it doesn't
+    // have one.
+    let data_pointers = program.data_pointers();
+    let block_index = |p: isize| data_pointers.iter().position(|&d| d == p);
+
+    let mut named: BTreeMap<usize, (&str, Value)> = BTreeMap::new();
+    for (name, &v) in program.constants.iter() {
+        if let Value::Str(p) | Value::Array(p) = v {
+            if let Some(i) = block_index(p) {
+                named.entry(i).or_insert((name.as_str(), v));
+            }
+        }
+    }
+
+    let mut literals = Vec::new();
+    let mut pushed_arrays = BTreeSet::new();
+    for instruction in instructions.iter() {
+        match *instruction {
+            Instruction::Push(v @ Value::Str(p)) if !named.values().any(|c| c.1 == v) => {
+                literals.push(block_index(p))
+            }
+            Instruction::Push(Value::Array(p)) => {
+                pushed_arrays.insert(block_index(p));
+            }
+            _ => {}
+        }
+    }
+
+    let first_literal = program
+        .data
+        .len()
+        .checked_sub(literals.len())
+        .filter(|&k| {
+            literals.iter().zip(k..).all(|(&i, j)| {
+                i == Some(j)
+                    && !named.contains_key(&j)
+                    && !pushed_arrays.contains(&Some(j))
+                    && string_literal(&program.data[j]).is_some()
+            })
+        })
+        .unwrap_or(program.data.len());
+
+    let mut constant_names: BTreeSet<String> = program.constants.keys().cloned().collect();
+    let mut constants: Vec<(String, Value, String)> = Vec::new();
+    for (i, block) in program.data[..first_literal].iter().enumerate() {
+        let (name, v) = match named.get(&i) {
+            Some(&(name, v)) => (name.to_string(), v),
+            None => {
+                let p = data_pointers[i];
+                let v = if pushed_arrays.contains(&Some(i)) || string_literal(block).is_none() {
+                    Value::Array(p)
+                } else {
+                    Value::Str(p)
+                };
+                (fresh(&mut constant_names, format!("D{}", i)), v)
+            }
+        };
+        let literal = match v {
+            Value::Str(_) => string_literal(block),
+            _ => int_list(block),
+        };
+        match literal {
+            Some(literal) => constants.push((name, v, literal)),
+            None => return Err(DisasmError::BadData { index: i }),
+        }
+    }
+
+    let push = |ip: Pointer, v: Value| match constants.iter().find(|c| c.1 == v) {
+        Some((name, _, _)) => Ok(format!("PushAddr {}", name)),
+        None => match v {
+            Value::Str(p) => block_index(p)
+                .filter(|&i| i >= first_literal)
+                .and_then(|i| string_literal(&program.data[i]))
+                .map(|literal| format!("Push {}", literal))
+                .ok_or(DisasmError::BadPointer { ip, pointer: p }),
+            Value::Array(p) => Err(DisasmError::BadPointer { ip, pointer: p }),
+            v => Ok(format!("Push {}", v)),
+        },
+    };
+
+    let mut out = String::new();
+    let mut depth: usize = 0;
+
+    for (name, sig) in program.natives.iter() {
+        out.push_str(&format!(
+            ".native {} {} -> {}\n",
+            name, sig.args, sig.results
+        ));
+    }
+
+    if !constants.is_empty() {
+        out.push_str(".data\n");
+        for (name, _, literal) in constants.iter() {
+            out.push_str(&format!("    {} {}\n", name, literal));
+        }
+        out.push_str(".code\n");
+    }
+
+    for (ip, instruction) in instructions.iter().enumerate() {
+        let text = match &lines[ip] {
+            Line::Proc(name) => match signature(program, name, ip) {
+                Some(sig) => format!("Proc {} {} -> {}", name, sig.args, sig.results),
+                None => format!("Proc {}", name),
+            },
+            Line::End => "End".to_string(),
+            Line::Label(name) => format!("label {}", name),
+            Line::Plain => match *instruction {
+                Instruction::Call(p) | Instruction::TailCall(p) => {
+                    format!("{} {}", instruction.name(), name_of(p - 1))
+                }
+                Instruction::CallNative(index) => match program.natives.get(index) {
+                    Some((name, _)) => format!("Call {}", name),
+                    None => return Err(DisasmError::BadNative { ip, index }),
+                },
+                Instruction::Push(v) => push(ip, v)?,
+                Instruction::Return(_, _) => "Ret".to_string(),
+                Instruction::RetN(_, keep) => format!("Ret {}", keep),
+                _ => match instruction.target() {
+                    Some(p) => format!("{} {}", instruction.name(), name_of(p)),
+                    None => instruction.to_string(),
+                },
+            },
+        };
+
+        if lines[ip] == Line::End {
+            depth = depth.saturating_sub(1);
+        }
+
+        out.push_str(&"    ".repeat(depth));
+        out.push_str(&text);
+        out.push('\n');
+
+        if let Line::Proc(_) = lines[ip] {
+            depth += 1;
+        }
+    }
+
+    Ok(out)
+}
+
+// signature returns the Signature of the procedure starting at start.
+// Without a symbol table, it's worked out from the Returns in its body.
+fn signature(program: &Program, name: &str, start: Pointer) -> Option<Signature> {
+    if let Some(&sig) = program.signatures.get(name) {
+        return Some(sig);
+    }
+
+    let end = program.instructions[start].target()?;
+    program
+        .instructions
+        .get(start + 1..end)?
+        .iter()
+        .find_map(|i| match *i {
+            Instruction::Return(args, results) => Some(Signature { args, results }),
+            Instruction::RetN(args, results) if args > 0 => Some(Signature { args, results }),
+            _ => None,
+        })
+}
+
+// string_literal writes a block of char codes as a quoted string,
+// escaping it the same way the assembler unescapes it.
+fn string_literal(chars: &[Value]) -> Option<String> {
+    let mut res = String::from("\"");
+    for &c in chars.iter() {
+        let c = match c {
+            Value::Int(c) => std::char::from_u32(u32::try_from(c).ok()?)?,
+            _ => return None,
+        };
+        match c {
+            '\n' => res.push_str("\\n"),
+            '\t' => res.push_str("\\t"),
+            '\\' => res.push_str("\\\\"),
+            '"' => res.push_str("\\\""),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    Some(res)
+}
+
+// int_list writes a block of ints as the values of an array constant.
+fn int_list(values: &[Value]) -> Option<String> {
+    let ints = values
+        .iter()
+        .map(|&v| match v {
+            Value::Int(i) => Some(i.to_string()),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    // An empty constant can't be declared
+    if ints.is_empty() {
+        None
+    } else {
+        Some(ints.join(" "))
+    }
+}
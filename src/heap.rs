@@ -0,0 +1,206 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+use crate::instruction::Pointer;
+use crate::value::Value;
+
+// The Heap is a linear block of memory which programs can allocate from,
+// on top of the Stack.
+//
+// Memory is addressed by isize pointers, which are just indices into
+// the Heap, and each word holds a Value. Pointer 0 is never handed out,
+// so it can be used as a null pointer.
+//
+// Every allocated block is remembered, so each access can be checked
+// against the block it's supposed to be in:
+//
+// Alloc     [.., 3] -> [.., p]         a block of 3 zeroed words at p
+// Load      [.., p, 1] -> [.., w]      p must be a live block, and 1 < 3
+// Free      [.., p] -> [..]            p must be a live block
+//
+// Freed blocks are kept in a free list, merged with their neighbours,
+// and reused first-fit before the Heap grows.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Heap {
+    pub(crate) memory: Vec<Value>,
+    // Live blocks, as start => (length, capacity). The capacity is
+    // at least 1, so that every block has a distinct address.
+    pub(crate) blocks: BTreeMap<Pointer, (usize, usize)>,
+    // Freed space, as start => capacity
+    pub(crate) free: BTreeMap<Pointer, usize>,
+}
+
+// The ways a heap access can fail
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeapError {
+    // The pointer isn't the start of a live block
+    InvalidPointer(isize),
+    // The offset is outside of the block
+    OutOfBounds { pointer: isize, offset: isize },
+    // A negative allocation size, or one too big to ever fit in memory
+    InvalidSize(isize),
+}
+
+impl std::fmt::Display for HeapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HeapError::InvalidPointer(p) => write!(f, "{} is not an allocated pointer", p),
+            HeapError::OutOfBounds { pointer, offset } => {
+                write!(
+                    f,
+                    "offset {} is out of bounds of the block at {}",
+                    offset, pointer
+                )
+            }
+            HeapError::InvalidSize(n) => write!(f, "can't allocate {} words", n),
+        }
+    }
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Heap::new()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Heap {
+            // The word at 0 is reserved for null
+            memory: vec![Value::default()],
+            blocks: BTreeMap::new(),
+            free: BTreeMap::new(),
+        }
+    }
+
+    // The total number of words the Heap takes up, allocated or not
+    pub fn size(&self) -> usize {
+        self.memory.len()
+    }
+
+    // The number of words in live blocks
+    pub fn allocated(&self) -> usize {
+        self.blocks.values().map(|&(_, capacity)| capacity).sum()
+    }
+
+    pub fn alloc(&mut self, len: isize) -> Result<isize, HeapError> {
+        if len < 0 {
+            return Err(HeapError::InvalidSize(len));
+        }
+        let len = len as usize;
+        let capacity = len.max(1);
+
+        // First fit from the free list, splitting off whatever's left
+        let found = self
+            .free
+            .iter()
+            .find(|(_, &free)| free >= capacity)
+            .map(|(&start, &free)| (start, free));
+
+        let start = match found {
+            Some((start, free)) => {
+                self.free.remove(&start);
+                if free > capacity {
+                    self.free.insert(start + capacity, free - capacity);
+                }
+                for word in self.memory[start..start + capacity].iter_mut() {
+                    *word = Value::default();
+                }
+                start
+            }
+            None => {
+                // Growing the Heap can fail, rather than abort the whole
+                // process, and every word has to have an isize pointer
+                let start = self.memory.len();
+                let end = start
+                    .checked_add(capacity)
+                    .filter(|&end| isize::try_from(end).is_ok())
+                    .ok_or(HeapError::InvalidSize(len as isize))?;
+                self.memory
+                    .try_reserve_exact(capacity)
+                    .map_err(|_| HeapError::InvalidSize(len as isize))?;
+                self.memory.resize(end, Value::default());
+                start
+            }
+        };
+
+        self.blocks.insert(start, (len, capacity));
+        Ok(start as isize)
+    }
+
+    // alloc_values allocates a block holding a copy of values.
+    pub fn alloc_values(&mut self, values: &[Value]) -> isize {
+        let pointer = self
+            .alloc(values.len() as isize)
+            .expect("a slice always fits in memory");
+        let start = pointer as usize;
+        self.memory[start..start + values.len()].copy_from_slice(values);
+        pointer
+    }
+
+    pub fn free(&mut self, pointer: isize) -> Result<(), HeapError> {
+        let (start, (_, mut capacity)) = self.block(pointer)?;
+        self.blocks.remove(&start);
+
+        let mut start = start;
+
+        // Merge with the free space right after the block...
+        if let Some(next) = self.free.remove(&(start + capacity)) {
+            capacity += next;
+        }
+
+        // ...and right before it
+        let prev = self
+            .free
+            .range(..start)
+            .next_back()
+            .map(|(&s, &c)| (s, c))
+            .filter(|&(s, c)| s + c == start);
+        if let Some((prev_start, prev_capacity)) = prev {
+            self.free.remove(&prev_start);
+            start = prev_start;
+            capacity += prev_capacity;
+        }
+
+        self.free.insert(start, capacity);
+        Ok(())
+    }
+
+    pub fn load(&self, pointer: isize, offset: isize) -> Result<Value, HeapError> {
+        let i = self.index(pointer, offset)?;
+        Ok(self.memory[i])
+    }
+
+    pub fn store(&mut self, pointer: isize, offset: isize, value: Value) -> Result<(), HeapError> {
+        let i = self.index(pointer, offset)?;
+        self.memory[i] = value;
+        Ok(())
+    }
+
+    // block_values returns the contents of the block at pointer.
+    pub fn block_values(&self, pointer: isize) -> Result<&[Value], HeapError> {
+        let (start, (len, _)) = self.block(pointer)?;
+        Ok(&self.memory[start..start + len])
+    }
+
+    // len returns the length of the block at pointer.
+    pub fn len(&self, pointer: isize) -> Result<usize, HeapError> {
+        self.block(pointer).map(|(_, (len, _))| len)
+    }
+
+    fn block(&self, pointer: isize) -> Result<(Pointer, (usize, usize)), HeapError> {
+        let start = usize::try_from(pointer).map_err(|_| HeapError::InvalidPointer(pointer))?;
+        self.blocks
+            .get(&start)
+            .map(|&block| (start, block))
+            .ok_or(HeapError::InvalidPointer(pointer))
+    }
+
+    fn index(&self, pointer: isize, offset: isize) -> Result<usize, HeapError> {
+        let (start, (len, _)) = self.block(pointer)?;
+        match usize::try_from(offset) {
+            Ok(offset) if offset < len => Ok(start + offset),
+            _ => Err(HeapError::OutOfBounds { pointer, offset }),
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use crate::value::Value;
+
+// Pointers are just indices into a Vec
+pub type Pointer = usize;
+
+// For simplicity, this VM runs off of tagged union
+// Instructions which carry data with them. For that reason,
+// this isn't strictly a *bytecode* interpreter, since instructions
+// take 24 bytes.
+//
+// Usually, you would want to store each Instruction
+// as just a discriminant (e.g. Push or Jump) so that
+// they fit in one byte each.
+//
+// The instruction arguments would then be read byte by byte from
+// the code.
+//
+// An explanation of the individual instructions is in
+// `Vm::execute()`, in vm.rs.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instruction {
+    Push(Value),
+    Pop,
+    Add,
+    Sub,
+    Incr,
+    Decr,
+    Mul,
+    Div,
+    Jump(Pointer),
+    JE(Pointer),
+    JNE(Pointer),
+    JGT(Pointer),
+    JLT(Pointer),
+    JGE(Pointer),
+    JLE(Pointer),
+    Get(Pointer),
+    Set(Pointer),
+    SetPop(Pointer),
+    GetArg(Pointer),
+    SetArg(Pointer),
+    Noop,
+    Print,
+    PrintC,
+    PrintStack,
+    Call(Pointer),
+    // A Call which reuses the current StackFrame, like `Call p; Ret`
+    TailCall(Pointer),
+    // A Call to a native function registered by the host, by its index
+    // in Program::natives
+    CallNative(usize),
+    Ret,
+    // What Ret compiles to in a procedure with a Signature
+    Return(usize, usize),
+    // Reserves n locals at the start of a procedure
+    Enter(usize),
+    // What `Ret n` compiles to, as (arguments to drop, values to keep)
+    RetN(usize, usize),
+    Alloc,
+    Free,
+    Load,
+    Store,
+    PrintS,
+    ReadInt,
+    ReadC,
+    // Calls into the host, by its number in the table in syscall.rs
+    Syscall(usize),
+    Halt,
+    Exit,
+}
+
+impl Instruction {
+    // name returns the mnemonic of the instruction, as written in assembly.
+    pub fn name(&self) -> &'static str {
+        use Instruction::*;
+
+        match self {
+            Push(_) => "Push",
+            Pop => "Pop",
+            Add => "Add",
+            Sub => "Sub",
+            Incr => "Incr",
+            Decr => "Decr",
+            Mul => "Mul",
+            Div => "Div",
+            Jump(_) => "Jump",
+            JE(_) => "JE",
+            JNE(_) => "JNE",
+            JGT(_) => "JGT",
+            JLT(_) => "JLT",
+            JGE(_) => "JGE",
+            JLE(_) => "JLE",
+            Get(_) => "Get",
+            Set(_) => "Set",
+            SetPop(_) => "SetPop",
+            GetArg(_) => "GetArg",
+            SetArg(_) => "SetArg",
+            Noop => "Noop",
+            Print => "Print",
+            PrintC => "PrintC",
+            PrintStack => "PrintStack",
+            Call(_) => "Call",
+            TailCall(_) => "TailCall",
+            CallNative(_) => "CallNative",
+            Ret => "Ret",
+            Return(_, _) => "Return",
+            Enter(_) => "Enter",
+            RetN(_, _) => "RetN",
+            Alloc => "Alloc",
+            Free => "Free",
+            Load => "Load",
+            Store => "Store",
+            PrintS => "PrintS",
+            ReadInt => "ReadInt",
+            ReadC => "ReadC",
+            Syscall(_) => "Syscall",
+            Halt => "Halt",
+            Exit => "Exit",
+        }
+    }
+
+    // target returns the instruction pointer that a jump or call
+    // can transfer control to.
+    pub fn target(&self) -> Option<Pointer> {
+        use Instruction::*;
+
+        match *self {
+            Jump(p) | JE(p) | JNE(p) | JGT(p) | JLT(p) | JGE(p) | JLE(p) | Call(p)
+            | TailCall(p) => Some(p),
+            _ => None,
+        }
+    }
+}
+
+// Instructions are displayed like assembly, except that jump and
+// call targets are shown as raw instruction pointers.
+//
+// Example: `Push 3`, `Jump 17`, `Add`
+impl std::fmt::Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use Instruction::*;
+
+        match *self {
+            Push(d) => write!(f, "{} {}", self.name(), d),
+            Jump(p) | JE(p) | JNE(p) | JGT(p) | JLT(p) | JGE(p) | JLE(p) | Get(p) | Set(p)
+            | SetPop(p) | GetArg(p) | SetArg(p) | Call(p) | TailCall(p) | CallNative(p)
+            | Enter(p) | Syscall(p) => {
+                write!(f, "{} {}", self.name(), p)
+            }
+            Return(a, b) | RetN(a, b) => write!(f, "{} {} {}", self.name(), a, b),
+            _ => write!(f, "{}", self.name()),
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use std::io::{BufRead, Write};
+use std::sync::{Arc, Mutex};
+
+// A Vm reads from one source and writes to two sinks:
+//
+// - its input, for ReadInt and ReadC, which is stdin by default
+// - its output, for Print, PrintC and PrintS, which is a buffered
+//   stdout by default
+// - its diagnostics, for PrintStack, which is stderr by default
+//
+// Keeping the sinks apart means debugging output doesn't get mixed into
+// what the program prints. Any of them can be swapped for anything
+// which implements BufRead or Write, with Vm::set_input, Vm::set_output
+// and Vm::set_diagnostics. A Cursor makes for scripted input:
+//
+// vm.set_input(Box::new(std::io::Cursor::new("1 2 3")));
+//
+// A Capture keeps everything written to it in memory, for tests or for
+// showing the output somewhere other than a terminal:
+//
+// let output = Capture::new();
+// vm.set_output(Box::new(output.clone()));
+// vm.run()?;
+// assert_eq!(output.contents(), "4950");
+
+pub type Source = Box<dyn BufRead + Send>;
+pub type Sink = Box<dyn Write + Send>;
+
+pub fn stdin() -> Source {
+    Box::new(std::io::BufReader::new(std::io::stdin()))
+}
+
+pub fn stdout() -> Sink {
+    Box::new(std::io::BufWriter::new(std::io::stdout()))
+}
+
+pub fn stderr() -> Sink {
+    Box::new(std::io::stderr())
+}
+
+// The Vm keeps its own buffer in front of its input, so that it can tell
+// when reading has to go to the Source, which might block waiting for
+// the user to type something. Whatever the program has printed is
+// flushed first, so that a prompt shows up before the program waits
+// for an answer, like C's stdio does. Flushing on every read instead
+// would make a program like cat.bytecode write one byte at a time.
+pub(crate) struct Input {
+    source: Source,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl Input {
+    pub(crate) fn new(source: Source) -> Self {
+        Input {
+            source,
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    // fill returns the buffered input, first calling before_wait if it
+    // has to read more from the Source. It's empty at the end of the input.
+    fn fill(
+        &mut self,
+        before_wait: &mut dyn FnMut() -> std::io::Result<()>,
+    ) -> std::io::Result<&[u8]> {
+        if self.pos >= self.buf.len() {
+            before_wait()?;
+            let data = self.source.fill_buf()?;
+            self.buf.clear();
+            self.buf.extend_from_slice(data);
+            self.pos = 0;
+            let n = data.len();
+            self.source.consume(n);
+        }
+        Ok(&self.buf[self.pos..])
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.pos += n;
+    }
+
+    // read_byte reads a single byte, or returns None at the end of the input.
+    pub(crate) fn read_byte(
+        &mut self,
+        before_wait: &mut dyn FnMut() -> std::io::Result<()>,
+    ) -> std::io::Result<Option<u8>> {
+        let byte = self.fill(before_wait)?.first().copied();
+        if byte.is_some() {
+            self.consume(1);
+        }
+        Ok(byte)
+    }
+
+    // read_word skips any whitespace, then reads everything up to the
+    // next whitespace. It returns an empty word at the end of the input.
+    pub(crate) fn read_word(
+        &mut self,
+        before_wait: &mut dyn FnMut() -> std::io::Result<()>,
+    ) -> std::io::Result<Vec<u8>> {
+        let mut word = Vec::new();
+        loop {
+            let buf = self.fill(before_wait)?;
+            if buf.is_empty() {
+                return Ok(word);
+            }
+
+            let skip = if word.is_empty() {
+                buf.iter().take_while(|b| b.is_ascii_whitespace()).count()
+            } else {
+                0
+            };
+            let len = buf[skip..]
+                .iter()
+                .take_while(|b| !b.is_ascii_whitespace())
+                .count();
+            word.extend_from_slice(&buf[skip..skip + len]);
+
+            let done = skip + len < buf.len();
+            self.consume(skip + len);
+            if done {
+                return Ok(word);
+            }
+        }
+    }
+
+    // read_line reads up to and including the next newline. It returns
+    // an empty line at the end of the input.
+    pub(crate) fn read_line(
+        &mut self,
+        before_wait: &mut dyn FnMut() -> std::io::Result<()>,
+    ) -> std::io::Result<Vec<u8>> {
+        let mut line = Vec::new();
+        loop {
+            let buf = self.fill(before_wait)?;
+            if buf.is_empty() {
+                return Ok(line);
+            }
+
+            let (len, done) = match buf.iter().position(|&b| b == b'\n') {
+                Some(i) => (i + 1, true),
+                None => (buf.len(), false),
+            };
+            line.extend_from_slice(&buf[..len]);
+            self.consume(len);
+            if done {
+                return Ok(line);
+            }
+        }
+    }
+}
+
+// Clones of a Capture share the same buffer, so one can be handed to
+// the Vm and another kept to read it back.
+#[derive(Debug, Clone, Default)]
+pub struct Capture(Arc<Mutex<Vec<u8>>>);
+
+impl Capture {
+    pub fn new() -> Self {
+        Capture::default()
+    }
+
+    // contents returns everything written so far. Anything which isn't
+    // valid UTF-8, like a PrintC of a byte over 127, is replaced.
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.bytes()).into_owned()
+    }
+
+    pub fn bytes(&self) -> Vec<u8> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut bytes = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        bytes.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
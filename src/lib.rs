@@ -0,0 +1,68 @@
+// TinyVM is a small stack-based bytecode VM.
+//
+// The crate is split into a few modules:
+//
+// - `instruction`: the instruction set
+// - `vm`: the interpreter itself, which runs a Program
+// - `heap`: the bounds checked memory used by Alloc, Free, Load and Store
+// - `value`: the Values which the VM operates on
+// - `asm`: a simplistic assembler which turns `.bytecode` source into a Program
+// - `program`: the assembled Program, along with its labels and procedures
+// - `bytecode`: the compact binary encoding of instructions
+// - `object`: the `.tvmc` object file format, which stores a Program
+// - `disasm`: a disassembler which turns a Program back into assembly
+// - `debugger`: an interactive step debugger
+// - `trace`: an execution tracer which logs every instruction
+// - `profile`: an instruction level profiler
+// - `opt`: a peephole optimizer which runs before interpreting
+// - `verify`: a static checker for unbalanced stacks
+// - `cfg`: basic blocks, control flow graphs and call graphs
+// - `snapshot`: saving and restoring the state of a running Vm
+// - `io`: where the Vm reads input from and sends what programs print
+// - `syscall`: the table of host operations behind Syscall, and the
+//   Capabilities which gate them
+//
+// A minimal embedding looks like:
+//
+// let program = tinyvm::Assembler::new("sum.bytecode").assemble(&source)?;
+// tinyvm::Vm::new(program).run()?;
+
+pub mod asm;
+pub mod bytecode;
+pub mod cfg;
+pub mod debugger;
+pub mod disasm;
+pub mod heap;
+pub mod instruction;
+pub mod io;
+pub mod object;
+pub mod opt;
+pub mod profile;
+pub mod program;
+pub mod snapshot;
+pub mod syscall;
+pub mod trace;
+pub mod value;
+pub mod verify;
+pub mod vm;
+
+pub use asm::{AsmError, Assembler, Span};
+pub use bytecode::DecodeError;
+pub use cfg::{CallGraph, Cfg};
+pub use debugger::Debugger;
+pub use disasm::{disassemble, DisasmError};
+pub use heap::{Heap, HeapError};
+pub use instruction::{Instruction, Pointer};
+pub use io::Capture;
+pub use opt::optimize;
+pub use profile::Profiler;
+pub use program::Program;
+pub use snapshot::SnapshotError;
+pub use syscall::{Capabilities, Capability};
+pub use trace::{TraceFormat, Tracer};
+pub use value::Value;
+pub use verify::{verify, VerifyError};
+pub use vm::{
+    CallStack, Limit, Native, RunStatus, StackFrame, Vm, VmConfig, VmError, VmErrorKind,
+    MAX_EXIT_STATUS,
+};
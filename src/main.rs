@@ -1,471 +1,549 @@
-use std::collections::BTreeMap;
-use std::io::Read;
-
-// Pointers are just indices into a Vec
-type Pointer = usize;
-
-// The Program is a list of instructions
-type Program<'a> = &'a [Instruction];
-
-// A Label is a name and an instruction pointer
-type Label<'a> = (&'a str, Pointer);
-type Labels<'a> = BTreeMap<&'a str, Pointer>;
-
-// A procedure has a name, a start instruction pointer,
-// and an end instruction pointer.
-// The ending instruction pointer is just used to skip over
-// the procedure.
-type Procedures<'a> = BTreeMap<&'a str, (Pointer, Pointer)>;
-
-// A StackFrame has an offset and an instruction pointer
-// to return to.
-// The offset is used for the Get/Set and GetArg/SetArg instructions.
-//
-// Example:
-// With stack [1, 3, 2] on instruction 24, call a procedure
-// at location 96.
-//
-// The stack frame to be pushed will look like:
-// StackFrame {
-//      stack_offset: 3, // the length of the stack before calling the procedure
-//      ip: i,
-// }
+use std::io::Write;
+use std::time::Duration;
+
+use tinyvm::{
+    disassemble, object, optimize, snapshot, verify, AsmError, Assembler, CallGraph, Capabilities,
+    Cfg, Debugger, Instruction, Limit, Profiler, Program, TraceFormat, Tracer, VerifyError, Vm,
+    VmConfig, VmError, VmErrorKind,
+};
+
+const USAGE: &str = "usage:
+    vm [run] [options] <file> [args]    run a .bytecode or .tvmc file, passing it args
+    vm assemble <file> [-o <output>]    assemble a .bytecode file into a .tvmc object file
+    vm disasm <file>                    print the assembly for a .bytecode or .tvmc file
+    vm debug [--input=<file>] <file>    run a file in the interactive debugger
+    vm verify <file>                    check that a file keeps its stack balanced
+    vm cfg [--dot] [--calls] <file>     print the basic blocks of a file, or its call graph
+
+run options:
+    -O0, -O1                            turn the peephole optimizer off (the default) or on
+    --trace[=<file>]                    log every instruction to stderr, or to a file
+    --trace-format=<text|json>          write the trace as text (the default) or JSON lines
+    --profile                           print a profile of the program to stderr when it ends
+    --profile-folded=<file>             also write the profile as folded stacks, for flamegraphs
+    --max-stack=<n>                     stop the program if its stack grows past n values
+    --max-calls=<n>                     stop the program if its call stack grows past n frames
+    --max-instructions=<n>              stop the program after n instructions
+    --max-heap=<n>                      stop the program if its heap grows past n words
+    --timeout=<seconds>                 stop the program after running for that long
+    --snapshot=<file>                   save the program's state if --max-instructions or --timeout stops it
+    --resume=<file>                     carry on from a snapshot of the same program
+    --sandbox                           don't let the program use files or environment variables";
+
+// The statuses `vm` exits with when something goes wrong, so that
+// scripts can tell what it was. They're the ones from BSD's sysexits.h,
+// which sit above the statuses a program can Exit with itself (up to
+// MAX_EXIT_STATUS) and below the ones from VmErrorKind::exit_code.
 //
-// The current instruction pointer will be set to 96.
-//
-// Inside the procedure, let's say a few values are pushed,
-// resulting in a stack [1, 3, 2, 5, 7, 4].
-//
-// Stack values are now accessed by Get/Set and GetArg/SetArg
-// relatively to the stack offset (denoted by the pipe '|'):
-//
-//       GetArg 2    GetArg 1    GetArg 0     Get 0       Get 1       Get 2
-// [        1,          3,          2,    |     5,          7,          4       ]
-struct StackFrame {
-    pub stack_offset: Pointer,
-    pub ip: Pointer,
+// The command line didn't make sense
+const EXIT_USAGE: i32 = 64;
+// The program didn't assemble, load or verify
+const EXIT_INVALID: i32 = 65;
+// A file couldn't be read or written
+const EXIT_ERROR: i32 = 74;
+
+// The options for running a program, parsed from the command line
+struct RunOptions<'a> {
+    file: &'a str,
+    // None if tracing is off, Some(None) to trace to stderr
+    trace: Option<Option<&'a str>>,
+    trace_format: TraceFormat,
+    profile: bool,
+    profile_folded: Option<&'a str>,
+    optimize: bool,
+    config: VmConfig,
+    snapshot: Option<&'a str>,
+    resume: Option<&'a str>,
+    // The arguments after the file, which are passed to the program
+    args: &'a [&'a str],
 }
 
-// The CallStack is just a Vec of StackFrames.
-type CallStack = Vec<StackFrame>;
-
-// Since the only values allowed by this VM are isizes,
-// the Stack is just a Vec of isizes.
-//
-// I made a wrapper type just to panic and crash the program
-// on any errors. In a real VM you'd want to add proper error
-// handling.
-struct Stack(Vec<isize>);
-
-impl Stack {
-    fn push(&mut self, v: isize) {
-        self.0.push(v);
-    }
-
-    fn pop(&mut self) -> isize {
-        self.0.pop().expect("popped an empty stack")
-    }
-
-    fn peek(&mut self) -> isize {
-        *self.0.last().expect("peeked an empty stack")
-    }
-
-    fn peek_mut(&mut self) -> &mut isize {
-        self.0.last_mut().expect("peeked an empty stack")
-    }
+impl<'a> RunOptions<'a> {
+    fn parse(args: &'a [&'a str]) -> Option<Self> {
+        let mut file = None;
+        let mut trace = None;
+        let mut trace_format = TraceFormat::Text;
+        let mut profile = false;
+        let mut profile_folded = None;
+        let mut optimize = false;
+        // Programs run from the command line are trusted unless they're
+        // sandboxed, since they run as the user anyway
+        let mut config = VmConfig {
+            capabilities: Capabilities::TRUSTED,
+            ..VmConfig::default()
+        };
+        let mut snapshot = None;
+        let mut resume = None;
+
+        for (i, &arg) in args.iter().enumerate() {
+            match arg {
+                "--trace" => trace = Some(None),
+                "--trace-format=text" => trace_format = TraceFormat::Text,
+                "--trace-format=json" => trace_format = TraceFormat::Json,
+                "--profile" => profile = true,
+                "-O0" => optimize = false,
+                "-O1" => optimize = true,
+                "--sandbox" => config.capabilities = Capabilities::SANDBOXED,
+                _ if arg.starts_with("--profile-folded=") => {
+                    profile = true;
+                    profile_folded = Some(&arg["--profile-folded=".len()..]);
+                }
+                _ if arg.starts_with("--trace=") => trace = Some(Some(&arg["--trace=".len()..])),
+                _ if arg.starts_with("--max-stack=") => {
+                    config.max_stack = Some(option_value(arg, "--max-stack=")?)
+                }
+                _ if arg.starts_with("--max-calls=") => {
+                    config.max_calls = Some(option_value(arg, "--max-calls=")?)
+                }
+                _ if arg.starts_with("--max-instructions=") => {
+                    config.max_instructions = Some(option_value(arg, "--max-instructions=")?)
+                }
+                _ if arg.starts_with("--max-heap=") => {
+                    config.max_heap = Some(option_value(arg, "--max-heap=")?)
+                }
+                _ if arg.starts_with("--timeout=") => {
+                    let seconds = option_value(arg, "--timeout=")?;
+                    config.timeout = Some(Duration::try_from_secs_f64(seconds).ok()?)
+                }
+                _ if arg.starts_with("--snapshot=") => snapshot = Some(&arg["--snapshot=".len()..]),
+                _ if arg.starts_with("--resume=") => resume = Some(&arg["--resume=".len()..]),
+                _ if arg.starts_with('-') => return None,
+                // Everything after the file is for the program
+                _ => {
+                    file = Some((arg, &args[i + 1..]));
+                    break;
+                }
+            }
+        }
+        let (file, args) = file?;
 
-    fn get(&self, i: usize) -> &isize {
-        self.0.get(i).expect("accessed a nonexistent stack index")
-    }
+        // Tracing and profiling both drive the Vm themselves
+        if trace.is_some() && profile {
+            return None;
+        }
 
-    fn get_mut(&mut self, i: usize) -> &mut isize {
-        self.0
-            .get_mut(i)
-            .expect("mutably accessed a nonexistent stack index")
+        Some(RunOptions {
+            file,
+            trace,
+            trace_format,
+            profile,
+            profile_folded,
+            optimize,
+            config,
+            snapshot,
+            resume,
+            args,
+        })
     }
 }
 
-// For simplicity, this VM runs off of tagged union
-// Instructions which carry data with them. For that reason,
-// this isn't strictly a *bytecode* interpreter, since instructions
-// take 16 bytes.
-//
-// Usually, you would want to store each Instruction
-// as just a discriminant (e.g. Push or Jump) so that
-// they fit in one byte each.
-//
-// The instruction arguments would then be read byte by byte from
-// the code.
-//
-// An explanation of the individual instructions is in the
-// `interpret()` function.
-#[derive(Debug)]
-enum Instruction {
-    Push(isize),
-    Pop,
-    Add,
-    Sub,
-    Incr,
-    Decr,
-    Mul,
-    Div,
-    Jump(Pointer),
-    JE(Pointer),
-    JNE(Pointer),
-    JGT(Pointer),
-    JLT(Pointer),
-    JGE(Pointer),
-    JLE(Pointer),
-    Get(Pointer),
-    Set(Pointer),
-    GetArg(Pointer),
-    SetArg(Pointer),
-    Noop,
-    Print,
-    PrintC,
-    PrintStack,
-    Call(Pointer),
-    Ret,
+// option_value parses the value of an option like `--max-stack=1000`
+fn option_value<T: std::str::FromStr>(arg: &str, name: &str) -> Option<T> {
+    arg.strip_prefix(name)?.parse().ok()
 }
 
-fn interpret<'a>(program: Program<'a>) {
-    use Instruction::*;
+fn print_asm_errors(file: &str, errors: &[AsmError]) {
+    for e in errors.iter() {
+        eprintln!("{}\n", e);
+    }
+    eprintln!(
+        "error: could not assemble `{}` due to {} previous error{}",
+        file,
+        errors.len(),
+        if errors.len() == 1 { "" } else { "s" }
+    );
+}
 
-    let mut stack: Stack = Stack(Vec::new());
-    let mut pointer: Pointer = 0;
-    let mut call_stack = CallStack::new();
+// load reads a Program from either an object file or assembly source,
+// depending on whether the file starts with the object file magic number.
+// Any errors are printed before returning the status to exit with.
+fn load(file: &str) -> Result<Program, i32> {
+    let bytes = match std::fs::read(file) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            eprintln!("error: could not read `{}`: {}", file, e);
+            return Err(EXIT_ERROR);
+        }
+    };
 
-    while let Some(instruction) = program.get(pointer) {
-        pointer += 1;
+    if object::is_object(&bytes) {
+        return object::read(&bytes).map_err(|e| {
+            eprintln!("error: could not load `{}`: {}", file, e);
+            EXIT_INVALID
+        });
+    }
 
-        match instruction {
-            // Noop doesn't do anything. However, it's used as a placeholder
-            // for labels and procedures in the code.
-            Noop => {}
+    let source = match String::from_utf8(bytes) {
+        Ok(source) => source,
+        Err(_) => {
+            eprintln!("error: `{}` is not valid UTF-8", file);
+            return Err(EXIT_INVALID);
+        }
+    };
 
-            // Push pushes a value to the top of the stack.
-            Push(d) => stack.push(*d),
+    Assembler::new(file).assemble(&source).map_err(|errors| {
+        print_asm_errors(file, &errors);
+        EXIT_INVALID
+    })
+}
 
-            // Pop removes a value from the top of the stack.
-            Pop => {
-                stack.pop();
-            }
+fn run(options: &RunOptions) -> i32 {
+    let mut program = match load(options.file) {
+        Ok(program) => program,
+        Err(code) => return code,
+    };
 
-            // Add pops the two top values, adds them, and pushes
-            // the result.
-            //
-            // Before:
-            // [.., a, b]
-            //
-            // After:
-            // [.., a + b]
-            Add => {
-                let (a, b) = (stack.pop(), stack.pop());
-                stack.push(a + b)
-            }
-
-            // Sub pops the two top values, and pushes the difference.
-            // Importantly, the order of operations is switched.
-            //
-            // This is a bit more intuitive because the stack is
-            // usually reasoned about from left to right.
-            //
-            // Before:
-            // [.., a, b]
-            //
-            // After:
-            // [.., b - a]
-            Sub => {
-                let (a, b) = (stack.pop(), stack.pop());
-                stack.push(b - a)
-            }
+    if options.optimize {
+        optimize(&mut program);
+    }
 
-            // I think you can figure out Mul and Div
-            Mul => {
-                let (a, b) = (stack.pop(), stack.pop());
-                stack.push(a * b)
-            }
-            Div => {
-                let (a, b) = (stack.pop(), stack.pop());
-                stack.push(b / a)
-            }
+    let mut vm = match options.resume {
+        None => Vm::with_config(program, options.config),
+        Some(path) => match resume(path, program, options.config) {
+            Some(vm) => vm,
+            None => return EXIT_ERROR,
+        },
+    };
+
+    // Nothing registers natives here, so a program which calls one is
+    // stopped before it starts rather than when it gets to the call
+    let errors = native_errors(vm.program(), &vm.unregistered_natives());
+    if !errors.is_empty() {
+        print_verify_errors(options.file, &errors);
+        eprintln!(
+            "error: could not run `{}` due to {} previous error{}",
+            options.file,
+            errors.len(),
+            if errors.len() == 1 { "" } else { "s" }
+        );
+        return EXIT_INVALID;
+    }
 
-            // Incr and Decr increment or decrement the value
-            // at the top of the stack.
-            //
-            // These instructions are redundant because of Add and Sub,
-            // but they improve performance significantly because they
-            // remove an unecessary Push.
-            Incr => *stack.peek_mut() += 1,
-            Decr => *stack.peek_mut() -= 1,
-
-            // Jump unconditionally changes the stack pointer
-            Jump(p) => pointer = *p,
-
-            // JE changes the stack pointer if the value
-            // on top of the stack is zero. This is generally
-            // used after Sub for equality testing, hence the
-            // name of the instruction, Jump (if) Equal.
-            //
-            // Example:
-            //
-            // PrintStack -- [.., a, b]
-            // Sub
-            // PrintStack -- [.., b - a] // this will be zero if a and b are equal
-            // JE i // jumps to Instruction i if a and b were equal.
-            JE(p) => {
-                if stack.peek() == 0 {
-                    stack.pop();
-                    pointer = *p;
+    // Like in C, the program's first argument is its own name
+    let args = std::iter::once(options.file).chain(options.args.iter().copied());
+    vm.set_args(args.map(String::from).collect());
+
+    let res = match options.trace {
+        None if options.profile => profile(&mut vm, options.profile_folded),
+        None => vm.run(),
+        Some(path) => {
+            let out: Box<dyn Write> = match path {
+                None => Box::new(std::io::stderr()),
+                Some(path) => match std::fs::File::create(path) {
+                    Ok(f) => Box::new(std::io::BufWriter::new(f)),
+                    Err(e) => {
+                        eprintln!("error: could not create `{}`: {}", path, e);
+                        return EXIT_ERROR;
+                    }
+                },
+            };
+
+            match Tracer::new(out, options.trace_format).run(&mut vm) {
+                Ok(res) => res,
+                Err(e) => {
+                    eprintln!("error: could not write trace: {}", e);
+                    return EXIT_ERROR;
                 }
             }
+        }
+    };
 
-            // JNE (Jump Not Equal) changes the stack pointer
-            // if the value on top of the stack is *not* zero.
-            JNE(p) => {
-                if stack.peek() != 0 {
-                    stack.pop();
-                    pointer = *p;
-                }
-            }
+    // The program's output is buffered, so it has to be written out
+    // before any errors are printed
+    if let Err(e) = vm.flush() {
+        eprintln!("error: could not write output: {}", e);
+        return EXIT_ERROR;
+    }
 
-            // JGT (Jump Greater Than) changes the stack pointer
-            // if the value on top of the stack is greater than zero.
-            JGT(p) => {
-                if stack.peek() > 0 {
-                    stack.pop();
-                    pointer = *p;
+    match res {
+        Ok(()) => vm.exit_status().unwrap_or(0),
+        Err(e) => {
+            eprintln!("{}", e);
+
+            // These limits stop the program before an instruction runs,
+            // so it can be resumed exactly where it left off
+            let resumable = matches!(
+                e.kind,
+                VmErrorKind::LimitExceeded(Limit::Instructions(_) | Limit::Time(_))
+            );
+            if let (true, Some(path)) = (resumable, options.snapshot) {
+                match std::fs::write(path, snapshot::write(&vm)) {
+                    Ok(()) => eprintln!("saved a snapshot to `{}`", path),
+                    Err(e) => eprintln!("error: could not write `{}`: {}", path, e),
                 }
             }
+            e.kind.exit_code()
+        }
+    }
+}
 
-            // JLT (Jump Less Than) changes the stack pointer
-            // if the value on top of the stack is less than zero.
-            JLT(p) => {
-                if stack.peek() < 0 {
-                    stack.pop();
-                    pointer = *p;
-                }
-            }
+// resume restores a Vm from a snapshot file, printing any errors
+// before returning None.
+fn resume(path: &str, program: Program, config: VmConfig) -> Option<Vm> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            eprintln!("error: could not read `{}`: {}", path, e);
+            return None;
+        }
+    };
 
-            // JGE (Jump Greater Equal) changes the stack pointer
-            // if the value on top of the stack is greater than
-            // or equal to zero.
-            JGE(p) => {
-                if stack.peek() >= 0 {
-                    stack.pop();
-                    pointer = *p;
-                }
-            }
+    snapshot::read(program, config, &bytes)
+        .map_err(|e| eprintln!("error: could not resume `{}`: {}", path, e))
+        .ok()
+}
 
-            // JLE (Jump Less Equal) changes the stack pointer
-            // if the value on top of the stack is greater than
-            // or equal to zero.
-            JLE(p) => {
-                if stack.peek() <= 0 {
-                    stack.pop();
-                    pointer = *p;
-                }
-            }
+// profile runs the Vm under the Profiler, printing a report to stderr
+// once it's done, even if the program faulted.
+fn profile(vm: &mut Vm, folded: Option<&str>) -> Result<(), VmError> {
+    let mut profiler = Profiler::new(vm.program());
+    let res = profiler.run(vm);
 
-            // The above instructions can be confusing because they
-            // don't quite match the naming semantics of JE and JNE.
-            // For example, when used after a Sub, JGT will jump if
-            // a was *less* than b, not greater than it, because
-            // if a is *less* than b, b - a will be *greater* than zero.
-
-            // Get pushes the value at index i to the top of the stack.
-            //
-            // Example:
-            //
-            // PrintStack -- [0, 1, 3, 2, 5]
-            // Get 2
-            // PrintStack -- [0, 1, 3, 2, 5, 3]
-            //
-            // Remember that values are indexed relatively to the stackframe
-            // as explained near the start of the file.
-            Get(i) => stack.push(*stack.get(*i + call_stack.last().map_or(0, |s| s.stack_offset))),
-
-            // Set sets the value at index i to be equal to the value
-            // at the top of the stack. It does *not* pop the top value.
-            //
-            // Example:
-            //
-            // PrintStack -- [0, 1, 3, 2, 5]
-            // Set 2
-            // PrintStack -- [0, 1, 5, 2, 5]
-            //
-            // Remember that values are indexed relatively to the stackframe
-            // as explained near the start of the file.
-            Set(i) => {
-                *stack
-                    .0
-                    .get_mut(*i + call_stack.last().map_or(0, |s| s.stack_offset))
-                    .unwrap() = stack.peek()
-            }
+    // Make sure the program's output comes before the report
+    vm.flush().ok();
 
-            // GetArg and SetArg mirror Get and Set.
-            GetArg(i) => stack.push(
-                *stack
-                    .0
-                    .get(call_stack.last().unwrap().stack_offset - 1 - *i)
-                    .unwrap(),
-            ),
-            SetArg(i) => {
-                let offset_i = call_stack.last().unwrap().stack_offset - 1 - *i;
-                let new_val = stack.peek();
-                *stack.get_mut(offset_i) = new_val;
-            }
+    let stderr = std::io::stderr();
+    if let Err(e) = profiler.write_report(vm.program(), &mut stderr.lock()) {
+        eprintln!("error: could not write profile: {}", e);
+    }
 
-            // Print prints the value at the top of the stack.
-            Print => print!("{}", stack.peek()),
-
-            // PrintC prints the value at the top of the stack
-            // as an ASCII character.
-            PrintC => print!("{}", stack.peek() as u8 as char),
-
-            // PrintStack prints the whole stack. It's meant to be
-            // used for debugging.
-            PrintStack => println!("{:?}", stack.0),
-
-            // Call calls a procedure, pushing a new StackFrame.
-            // Details about the StackFrame can be found near the
-            // start of the file.
-            Call(p) => {
-                call_stack.push(StackFrame {
-                    stack_offset: stack.0.len(),
-                    ip: pointer,
-                });
-                pointer = *p;
-            }
+    if let Some(path) = folded {
+        let written = std::fs::File::create(path)
+            .map(std::io::BufWriter::new)
+            .and_then(|mut f| profiler.write_folded(&mut f).and_then(|_| f.flush()));
+        if let Err(e) = written {
+            eprintln!("error: could not write `{}`: {}", path, e);
+        }
+    }
 
-            // Ret returns from the current procedure, popping the
-            // stack frame from the top of the call stack and returning
-            // to the instruction list at the index right after it was called at.
-            Ret => pointer = call_stack.pop().unwrap().ip,
+    res
+}
+
+fn assemble(file: &str, output: Option<&str>) -> i32 {
+    let program = match load(file) {
+        Ok(program) => program,
+        Err(code) => return code,
+    };
+
+    // By default, foo.bytecode is assembled to foo.tvmc
+    let output = output.map_or_else(
+        || {
+            std::path::Path::new(file)
+                .with_extension("tvmc")
+                .to_string_lossy()
+                .into_owned()
+        },
+        str::to_string,
+    );
+
+    match std::fs::write(&output, object::write(&program)) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("error: could not write `{}`: {}", output, e);
+            EXIT_ERROR
         }
     }
 }
 
-// The next instructions aren't really part of the VM, they are essentially
-// an extremely simplistic compiler. That's because the VM doesn't quite
-// support labels. It doesn't support named procedures either; it specifies
-// for procedures that contain an index into the Instruction list. Additionally,
-// this simplistic compiler allows for some basic comments.
+fn disasm(file: &str) -> i32 {
+    let program = match load(file) {
+        Ok(program) => program,
+        Err(code) => return code,
+    };
 
-// `parse_instruction` takes a line split by spaces and returns a singular
-// instruction that the line represents.
-//
-// Labels must be obtained by preprocessing the string, it's just a map of
-// names to their index in the instruction set.
-//
-// Procedures is similar, but it contains the instruction to jump to when called
-// along with the instruction to jump to in order to skip the Procedure declaration.
-// Procedures are encoded directly into the list of instructions, so the actual
-// Procedure declaration is replaced by a Jump instruction to skip over it.
-//
-// Example:
-//
-// Procedure proc_name -- Line n
-// ...
-// End -- line e
-//
-// Gets turned into
-//
-// Jump e -- Line n
-// ...
-// Noop -- line e
-fn parse_instruction(s: &[&str], labels: &Labels, procedures: &Procedures) -> Instruction {
-    use Instruction::*;
-
-    match s {
-        ["Push", x] => Push(x.parse::<isize>().unwrap()),
-        ["Pop"] => Pop,
-        ["Add"] => Add,
-        ["Sub"] => Sub,
-        ["Mul"] => Mul,
-        ["Div"] => Div,
-        ["Incr"] => Incr,
-        ["Decr"] => Decr,
-        ["Jump", l] => Jump(*labels.get(l).unwrap()),
-        ["JE", l] => JE(*labels.get(l).unwrap()),
-        ["JNE", l] => JNE(*labels.get(l).unwrap()),
-        ["JGE", l] => JGE(*labels.get(l).unwrap()),
-        ["JLE", l] => JLE(*labels.get(l).unwrap()),
-        ["JGT", l] => JGT(*labels.get(l).unwrap()),
-        ["JLT", l] => JLT(*labels.get(l).unwrap()),
-        ["Get", p] => Get(p.parse::<Pointer>().unwrap()),
-        ["Set", p] => Set(p.parse::<Pointer>().unwrap()),
-        ["GetArg", p] => GetArg(p.parse::<Pointer>().unwrap()),
-        ["SetArg", p] => SetArg(p.parse::<Pointer>().unwrap()),
-        ["Print"] => Print,
-        ["PrintC"] => PrintC,
-        ["PrintStack"] => PrintStack,
-        ["Proc", proc] => Jump(procedures.get(proc).unwrap().1),
-        ["Call", proc] => Call(procedures.get(proc).unwrap().0 + 1),
-        ["Ret"] => Ret,
-        ["label", ..] | ["End"] => Noop,
-        l => panic!("Invalid instruction: {:?}", l),
+    match disassemble(&program) {
+        Ok(source) => {
+            print!("{}", source);
+            0
+        }
+        Err(e) => {
+            eprintln!("error: could not disassemble `{}`: {}", file, e);
+            EXIT_INVALID
+        }
     }
 }
 
-// find_label takes a line split by spaces and the label it represents,
-// or None if it does not represent a label.
-fn find_label<'a>(i: Pointer, s: &'a [&'a str]) -> Option<Label> {
-    if let ["label", l] = s {
-        Some((l, i))
-    } else {
-        None
+fn verify_file(file: &str) -> i32 {
+    let program = match load(file) {
+        Ok(program) => program,
+        Err(code) => return code,
+    };
+
+    // `vm` doesn't register any natives, so a program which calls one
+    // can't run here even if it's balanced
+    let natives: Vec<&str> = program.natives.iter().map(|(n, _)| n.as_str()).collect();
+    let mut errors = native_errors(&program, &natives);
+    if let Err(e) = verify(&program) {
+        errors.extend(e);
+    }
+    if errors.is_empty() {
+        return 0;
     }
+    errors.sort_by_key(|e| e.ip);
+
+    print_verify_errors(file, &errors);
+    eprintln!(
+        "error: `{}` failed to verify due to {} previous error{}",
+        file,
+        errors.len(),
+        if errors.len() == 1 { "" } else { "s" }
+    );
+    EXIT_INVALID
 }
 
-// find_procedures takes a list of lines split on space and
-// returns the procedures declared.
-fn find_procedures<'a>(lines: &'a [Vec<&str>]) -> Procedures<'a> {
-    let mut ip = 0;
-    let mut res = Procedures::new();
-
-    while ip < lines.len() {
-        if let ["Proc", proc_name] = lines[ip].as_slice() {
-            let start_ip = ip;
-            while lines[ip] != &["End"] {
-                ip += 1;
+// native_errors reports the first call to each of the given natives.
+fn native_errors(program: &Program, unregistered: &[&str]) -> Vec<VerifyError> {
+    let mut reported = Vec::new();
+    program
+        .instructions
+        .iter()
+        .enumerate()
+        .filter_map(|(ip, instruction)| match *instruction {
+            Instruction::CallNative(i) => {
+                let (name, _) = program.natives.get(i)?;
+                if !unregistered.contains(&name.as_str()) || reported.contains(&i) {
+                    return None;
+                }
+                reported.push(i);
+                Some(VerifyError {
+                    ip,
+                    span: program.span(ip),
+                    message: format!("`{}` is a native function, which `vm` doesn't have", name),
+                })
             }
-            res.insert(proc_name, (start_ip, ip + 1));
-        } else {
-            ip += 1;
+            _ => None,
+        })
+        .collect()
+}
+
+// print_verify_errors prints errors against the source when there is
+// some.
+fn print_verify_errors(file: &str, errors: &[VerifyError]) {
+    let source = std::fs::read_to_string(file).ok();
+    for e in errors.iter() {
+        let line = e
+            .span
+            .and_then(|span| Some((span, source.as_ref()?.lines().nth(span.line - 1)?)));
+        match line {
+            Some((span, line)) => {
+                let e = AsmError {
+                    file: file.to_string(),
+                    span,
+                    message: e.message.clone(),
+                    source_line: line.to_string(),
+                };
+                eprintln!("{}\n", e);
+            }
+            None => eprintln!("error: {}\n", e),
         }
     }
-
-    res
 }
 
-fn main() -> std::io::Result<()> {
-    let args: Vec<String> = std::env::args().collect();
-    let mut f = std::fs::File::open(&args[1])?;
-
-    let mut buffer = String::new();
-    f.read_to_string(&mut buffer)?;
-
-    let line_splits = buffer
-        .split('\n')
-        .map(|s| s.split_whitespace().collect::<Vec<_>>())
-        .filter(|s| !matches!(s.as_slice(), [] | ["--", ..]))
-        .collect::<Vec<_>>();
+// cfg prints the control flow graph or the call graph of a file, either
+// as a listing or as Graphviz.
+fn cfg(args: &[&str]) -> i32 {
+    let (mut dot, mut calls, mut file) = (false, false, None);
+    for &arg in args.iter() {
+        match arg {
+            "--dot" => dot = true,
+            "--calls" => calls = true,
+            _ if arg.starts_with('-') || file.is_some() => {
+                eprintln!("{}", USAGE);
+                return EXIT_USAGE;
+            }
+            _ => file = Some(arg),
+        }
+    }
 
-    let labels: Labels = line_splits
-        .iter()
-        .enumerate()
-        .filter_map(|(i, s)| find_label(i, s.as_slice()))
-        .collect();
+    let file = match file {
+        Some(file) => file,
+        None => {
+            eprintln!("{}", USAGE);
+            return EXIT_USAGE;
+        }
+    };
+
+    let program = match load(file) {
+        Ok(program) => program,
+        Err(code) => return code,
+    };
+
+    match (calls, dot) {
+        (false, false) => print!("{}", Cfg::new(&program.instructions)),
+        (false, true) => print!("{}", Cfg::new(&program.instructions).to_dot(&program)),
+        (true, false) => print!("{}", CallGraph::new(&program)),
+        (true, true) => print!("{}", CallGraph::new(&program).to_dot()),
+    }
+    0
+}
 
-    let procedures: Procedures = find_procedures(line_splits.as_slice());
+fn debug(args: &[&str]) -> i32 {
+    let (file, input) = match args {
+        [file] if !file.starts_with('-') => (*file, None),
+        [option, file] if option.starts_with("--input=") => {
+            (*file, Some(&option["--input=".len()..]))
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            return EXIT_USAGE;
+        }
+    };
+
+    let program = match load(file) {
+        Ok(program) => program,
+        Err(code) => return code,
+    };
+
+    // The source is only used to show the current line, so it's
+    // fine if there isn't any.
+    let source = std::fs::read_to_string(file)
+        .ok()
+        .filter(|_| !program.spans.is_empty());
+
+    // The debugger reads its commands from stdin, so the program can't
+    // read from it too. It gets the input file instead, or no input.
+    let mut vm = Vm::new(program);
+    match input {
+        None => vm.set_input(Box::new(std::io::empty())),
+        Some(path) => match std::fs::File::open(path) {
+            Ok(f) => vm.set_input(Box::new(std::io::BufReader::new(f))),
+            Err(e) => {
+                eprintln!("error: could not read `{}`: {}", path, e);
+                return EXIT_ERROR;
+            }
+        },
+    }
 
-    let instructions: Vec<Instruction> = line_splits
-        .iter()
-        .map(|s| parse_instruction(s.as_slice(), &labels, &procedures))
-        .collect();
+    let stdin = std::io::stdin();
+    let mut debugger = Debugger::new(vm, source);
+    match debugger.run(stdin.lock(), &mut std::io::stdout()) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            EXIT_ERROR
+        }
+    }
+}
 
-    interpret(&instructions[..]);
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let code = match args.as_slice() {
+        ["assemble", file] => assemble(file, None),
+        ["assemble", file, "-o", output] => assemble(file, Some(output)),
+        ["debug", rest @ ..] => debug(rest),
+        ["disasm", file] => disasm(file),
+        ["verify", file] => verify_file(file),
+        ["cfg", rest @ ..] => cfg(rest),
+        ["run", rest @ ..] | rest => match RunOptions::parse(rest) {
+            Some(options) => run(&options),
+            None => {
+                eprintln!("{}", USAGE);
+                EXIT_USAGE
+            }
+        },
+    };
 
-    Ok(())
+    std::process::exit(code)
 }
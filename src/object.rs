@@ -0,0 +1,185 @@
+use crate::bytecode::{self, write_uleb, write_value, DecodeError, Reader};
+use crate::program::{Program, Signature};
+
+// The `.tvmc` object file format stores an assembled Program, so that
+// it can be run without the original source.
+//
+// All numbers are unsigned LEB128 (see bytecode.rs), and strings are
+// a length followed by that many bytes of UTF-8.
+//
+// magic        b"TVMC"
+// version      1 byte, currently 5
+//
+// code         length in bytes, then the encoded instructions
+// data         count, then for each block of constant data:
+//                  length, then that many tagged Values
+// natives      count, then for each native function the program
+//                  declares, in the order CallNative numbers them:
+//                  name, args, results
+//
+// labels       count, then for each label:
+//                  name, ip
+// procedures   count, then for each procedure:
+//                  name, start ip, end ip
+// constants    count, then for each constant:
+//                  name, tagged Value
+// signatures   count, then for each procedure with a signature:
+//                  name, args, results
+//
+// The labels, procedures, constants and signatures make up the symbol
+// table. The VM doesn't need them, but they keep the names around for
+// other tools.
+//
+// Source spans aren't stored, since object files are meant to be
+// distributed without their source.
+
+pub const MAGIC: &[u8; 4] = b"TVMC";
+pub const VERSION: u8 = 5;
+
+// is_object checks whether some bytes look like an object file,
+// rather than assembly source.
+pub fn is_object(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    write_uleb(out, s.len() as u64);
+    out.extend_from_slice(s.as_bytes());
+}
+
+pub fn write(program: &Program) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    out.push(VERSION);
+
+    let code = bytecode::encode(&program.instructions);
+    write_uleb(&mut out, code.len() as u64);
+    out.extend_from_slice(&code);
+
+    write_uleb(&mut out, program.data.len() as u64);
+    for block in program.data.iter() {
+        write_uleb(&mut out, block.len() as u64);
+        for &v in block.iter() {
+            write_value(&mut out, v);
+        }
+    }
+
+    write_uleb(&mut out, program.natives.len() as u64);
+    for (name, sig) in program.natives.iter() {
+        write_str(&mut out, name);
+        write_uleb(&mut out, sig.args as u64);
+        write_uleb(&mut out, sig.results as u64);
+    }
+
+    write_uleb(&mut out, program.labels.len() as u64);
+    for (name, ip) in program.labels.iter() {
+        write_str(&mut out, name);
+        write_uleb(&mut out, *ip as u64);
+    }
+
+    write_uleb(&mut out, program.procedures.len() as u64);
+    for (name, (start, end)) in program.procedures.iter() {
+        write_str(&mut out, name);
+        write_uleb(&mut out, *start as u64);
+        write_uleb(&mut out, *end as u64);
+    }
+
+    write_uleb(&mut out, program.constants.len() as u64);
+    for (name, &v) in program.constants.iter() {
+        write_str(&mut out, name);
+        write_value(&mut out, v);
+    }
+
+    write_uleb(&mut out, program.signatures.len() as u64);
+    for (name, sig) in program.signatures.iter() {
+        write_str(&mut out, name);
+        write_uleb(&mut out, sig.args as u64);
+        write_uleb(&mut out, sig.results as u64);
+    }
+
+    out
+}
+
+// count reads a signature's count, which the assembler only allows up
+// to isize::MAX
+fn count(r: &mut Reader) -> Result<usize, DecodeError> {
+    let offset = r.offset();
+    match r.usize()? {
+        n if n <= isize::MAX as usize => Ok(n),
+        _ => Err(DecodeError::Overflow { offset }),
+    }
+}
+
+pub fn read(bytes: &[u8]) -> Result<Program, DecodeError> {
+    let mut r = Reader::new(bytes);
+
+    if r.bytes(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+        return Err(DecodeError::BadMagic);
+    }
+
+    let version = r.byte()?;
+    if version != VERSION {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+
+    // The code is decoded in place, rather than with bytecode::decode,
+    // so that error offsets are relative to the start of the file.
+    let code_len = r.usize()?;
+    let code_end = r
+        .offset()
+        .checked_add(code_len)
+        .ok_or(DecodeError::UnexpectedEof)?;
+    let mut instructions = Vec::new();
+    while r.offset() < code_end {
+        instructions.push(bytecode::decode_instruction(&mut r)?);
+    }
+    if r.offset() != code_end {
+        return Err(DecodeError::UnexpectedEof);
+    }
+
+    let mut program = Program::new(instructions);
+
+    for _ in 0..r.usize()? {
+        let block = (0..r.usize()?)
+            .map(|_| r.value())
+            .collect::<Result<Vec<_>, _>>()?;
+        program.data.push(block);
+    }
+
+    for _ in 0..r.usize()? {
+        let name = r.str()?.to_string();
+        let (args, results) = (count(&mut r)?, count(&mut r)?);
+        program.natives.push((name, Signature { args, results }));
+    }
+
+    for _ in 0..r.usize()? {
+        let name = r.str()?;
+        program.labels.insert(name.to_string(), r.usize()?);
+    }
+
+    // Tools take a procedure's bounds on trust, so they're checked here
+    for _ in 0..r.usize()? {
+        let offset = r.offset();
+        let name = r.str()?;
+        let (start, end) = (r.usize()?, r.usize()?);
+        if start >= end || end > program.instructions.len() {
+            return Err(DecodeError::InvalidProcedure { offset });
+        }
+        program.procedures.insert(name.to_string(), (start, end));
+    }
+
+    for _ in 0..r.usize()? {
+        let name = r.str()?;
+        program.constants.insert(name.to_string(), r.value()?);
+    }
+
+    for _ in 0..r.usize()? {
+        let name = r.str()?;
+        let (args, results) = (count(&mut r)?, count(&mut r)?);
+        program
+            .signatures
+            .insert(name.to_string(), Signature { args, results });
+    }
+
+    Ok(program)
+}
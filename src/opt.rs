@@ -0,0 +1,207 @@
+use std::collections::BTreeSet;
+
+use crate::cfg::leaders;
+use crate::instruction::{Instruction, Pointer};
+use crate::program::Program;
+use crate::value::Value;
+use crate::vm::binary_op;
+
+// This module is a peephole optimizer. It runs between assembling and
+// interpreting, and rewrites short runs of instructions into cheaper
+// ones without changing what the program prints:
+//
+// Push 1; Add          ->  Incr
+// Push 1; Sub          ->  Decr
+// Push a; Push b; Add  ->  Push (a + b)    (likewise Sub, Mul and Div)
+// Push a; Incr         ->  Push (a + 1)    (likewise Decr)
+// Set n; Pop           ->  SetPop n
+// Call x; Ret          ->  TailCall x; Ret
+// Jump a; ...; a: Jump b  ->  Jump b; ...; a: Jump b
+//
+// Rewrites pad the instructions they replace with Noops, so that no
+// instruction pointers change. After each round of rewrites the Noops
+// are removed, and every jump, call, label, procedure and span is
+// fixed up to match. Labels and Ends are Noops, so this removes them
+// too, which is why the passes are repeated until nothing changes:
+// removing a label can put two Pushes next to each other.
+//
+// A run of instructions is only rewritten if it's inside a single basic
+// block (see cfg.rs), since anything jumping into the middle of it
+// would see a different program.
+//
+// Incr and Decr also work on floats, where `Push 1; Add` faults, so
+// a program which would have faulted with a type error can behave
+// differently. Likewise a `Call x; Ret` outside of any procedure faults
+// at the TailCall, before x runs, rather than at the Ret. Programs which
+// run without faulting aren't affected.
+
+pub fn optimize(program: &mut Program) {
+    loop {
+        let mut changed = peephole(program);
+        changed |= thread_jumps(program);
+        changed |= remove_noops(program);
+        if !changed {
+            return;
+        }
+    }
+}
+
+// rewrite tries each pattern on the instructions at the front of
+// window, returning what to replace them with, padded with Noops to
+// the same length.
+fn rewrite(window: &[Instruction]) -> Option<Vec<Instruction>> {
+    use Instruction::*;
+
+    let res = match *window {
+        [Push(Value::Int(1)), Add, ..] => vec![Incr, Noop],
+        [Push(Value::Int(1)), Sub, ..] => vec![Decr, Noop],
+        [Push(a), Push(b), op, ..] if matches!(op, Add | Sub | Mul | Div) => {
+            // Anything that would fault is left for the VM to report
+            vec![Push(binary_op(op, b, a).ok()?), Noop, Noop]
+        }
+        [Push(Value::Int(a)), Incr, ..] => vec![Push(Value::Int(a.checked_add(1)?)), Noop],
+        [Push(Value::Int(a)), Decr, ..] => vec![Push(Value::Int(a.checked_sub(1)?)), Noop],
+        [Set(n), Pop, ..] => vec![SetPop(n), Noop],
+        // The Ret is left alone, since something else could jump to it
+        [Call(p), Ret, ..] => vec![TailCall(p)],
+        _ => return None,
+    };
+
+    Some(res)
+}
+
+// peephole applies the rewrites, returning whether any were made.
+fn peephole(program: &mut Program) -> bool {
+    let leaders = leaders(&program.instructions);
+    let instructions = &mut program.instructions;
+    let mut changed = false;
+
+    let mut ip = 0;
+    while ip < instructions.len() {
+        let replacement = match rewrite(&instructions[ip..]) {
+            Some(r) => r,
+            None => {
+                ip += 1;
+                continue;
+            }
+        };
+
+        let len = replacement.len();
+        if leaders.range(ip + 1..ip + len).next().is_some() {
+            ip += 1;
+            continue;
+        }
+
+        instructions[ip..ip + len].copy_from_slice(&replacement);
+        changed = true;
+        ip += len;
+    }
+
+    changed
+}
+
+// thread_jumps points jumps which land on an unconditional Jump
+// straight at its target, returning whether any were changed.
+fn thread_jumps(program: &mut Program) -> bool {
+    let instructions = &mut program.instructions;
+    let mut changed = false;
+
+    for ip in 0..instructions.len() {
+        let mut target = match instructions[ip] {
+            Instruction::Call(_) | Instruction::TailCall(_) => continue,
+            instruction => match instruction.target() {
+                Some(target) => target,
+                None => continue,
+            },
+        };
+
+        // A chain can loop back on itself, in which case the program
+        // loops forever anyway, so the jump is left alone.
+        let original = target;
+        let mut seen = BTreeSet::new();
+        while let Some(&Instruction::Jump(next)) = instructions.get(target) {
+            if !seen.insert(target) {
+                target = original;
+                break;
+            }
+            target = next;
+        }
+
+        if target != original {
+            set_target(&mut instructions[ip], target);
+            changed = true;
+        }
+    }
+
+    changed
+}
+
+fn set_target(instruction: &mut Instruction, target: Pointer) {
+    use Instruction::*;
+
+    match instruction {
+        Jump(p) | JE(p) | JNE(p) | JGT(p) | JLT(p) | JGE(p) | JLE(p) | Call(p) | TailCall(p) => {
+            *p = target
+        }
+        _ => {}
+    }
+}
+
+// remove_noops deletes every Noop, returning whether there were any.
+//
+// An instruction's new pointer is the number of instructions kept
+// before it, which also works for the Noops being removed: anything
+// pointing at one ends up pointing at whatever followed it.
+fn remove_noops(program: &mut Program) -> bool {
+    let instructions = &program.instructions;
+    if !instructions.contains(&Instruction::Noop) {
+        return false;
+    }
+
+    let mut new_ip = Vec::with_capacity(instructions.len() + 1);
+    let mut kept = 0;
+    for instruction in instructions.iter() {
+        new_ip.push(kept);
+        if *instruction != Instruction::Noop {
+            kept += 1;
+        }
+    }
+    new_ip.push(kept);
+
+    // Targets past the end of the program just stop it, so they
+    // stay past the end.
+    let remap = |p: Pointer| match new_ip.get(p) {
+        Some(&new) => new,
+        None => kept + (p - instructions.len()),
+    };
+
+    let mut res = Vec::with_capacity(kept);
+    let mut spans = Vec::with_capacity(kept);
+    for (ip, instruction) in instructions.iter().enumerate() {
+        if *instruction == Instruction::Noop {
+            continue;
+        }
+
+        let mut instruction = *instruction;
+        if let Some(target) = instruction.target() {
+            set_target(&mut instruction, remap(target));
+        }
+        res.push(instruction);
+
+        if let Some(span) = program.spans.get(ip) {
+            spans.push(*span);
+        }
+    }
+
+    for ip in program.labels.values_mut() {
+        *ip = remap(*ip);
+    }
+    for (start, end) in program.procedures.values_mut() {
+        *start = remap(*start);
+        *end = remap(*end);
+    }
+
+    program.instructions = res;
+    program.spans = spans;
+    true
+}
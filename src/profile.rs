@@ -0,0 +1,313 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use crate::instruction::{Instruction, Pointer};
+use crate::program::Program;
+use crate::vm::{Vm, VmError};
+
+// A Profiler runs a Vm while counting every executed instruction.
+//
+// It keeps track of:
+//
+// - how often each opcode and each instruction pointer runs
+// - how often each backwards jump is taken, which is where loops are
+// - calls, inclusive, and exclusive instruction counts for each procedure
+// - instruction counts for every distinct chain of calls, which is
+//   what's written out as folded stacks for flamegraphs
+//
+// Procedures are tracked with a shadow call stack which follows the
+// Vm's CallStack: whenever a Call pushes a StackFrame, the procedure
+// being called is pushed, and whenever a Ret pops one it's popped. A
+// TailCall pops one and pushes another.
+// Code outside of any procedure is attributed to `main`.
+//
+// Inclusive counts include everything a procedure's callees ran. A
+// recursive procedure is only counted once, by its outermost call.
+pub struct Profiler {
+    total: u64,
+    opcodes: BTreeMap<&'static str, u64>,
+    ips: Vec<u64>,
+    back_edges: BTreeMap<(Pointer, Pointer), u64>,
+
+    // Procedure names, indexed by the ids used below. 0 is `main`.
+    names: Vec<String>,
+    entries: BTreeMap<Pointer, usize>,
+    procs: Vec<ProcStats>,
+
+    // The call chains form a tree, with `main` at the root
+    nodes: Vec<Node>,
+    // The shadow call stack, as (node, total when called) pairs
+    stack: Vec<(usize, u64)>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct ProcStats {
+    calls: u64,
+    inclusive: u64,
+    exclusive: u64,
+    // How many calls of the procedure are on the stack
+    active: u64,
+}
+
+struct Node {
+    proc: usize,
+    parent: Option<usize>,
+    children: BTreeMap<usize, usize>,
+    count: u64,
+}
+
+impl Profiler {
+    pub fn new(program: &Program) -> Self {
+        let mut names = vec!["main".to_string()];
+        let mut entries = BTreeMap::new();
+        for (name, &(start, _)) in program.procedures.iter() {
+            entries.insert(start + 1, names.len());
+            names.push(name.clone());
+        }
+
+        Profiler {
+            total: 0,
+            opcodes: BTreeMap::new(),
+            ips: vec![0; program.instructions.len()],
+            back_edges: BTreeMap::new(),
+            procs: vec![ProcStats::default(); names.len()],
+            names,
+            entries,
+            nodes: vec![Node {
+                proc: 0,
+                parent: None,
+                children: BTreeMap::new(),
+                count: 0,
+            }],
+            stack: vec![(0, 0)],
+        }
+    }
+
+    // run runs the Vm to completion while profiling it.
+    pub fn run(&mut self, vm: &mut Vm) -> Result<(), VmError> {
+        while self.step(vm)? {}
+        Ok(())
+    }
+
+    // step runs and counts a single instruction, like Vm::step.
+    pub fn step(&mut self, vm: &mut Vm) -> Result<bool, VmError> {
+        let ip = vm.pointer();
+        let instruction = match vm.program().instructions.get(ip) {
+            Some(&instruction) => instruction,
+            None => {
+                self.finish();
+                return Ok(false);
+            }
+        };
+        let depth = vm.call_stack().len();
+
+        self.total += 1;
+        *self.opcodes.entry(instruction.name()).or_insert(0) += 1;
+        self.ips[ip] += 1;
+
+        let (node, _) = *self.stack.last().expect("main is never popped");
+        self.nodes[node].count += 1;
+        self.procs[self.nodes[node].proc].exclusive += 1;
+
+        // The instruction is counted even if it faults
+        let running = match vm.step() {
+            Ok(running) => running,
+            Err(e) => {
+                self.finish();
+                return Err(e);
+            }
+        };
+
+        let new_depth = vm.call_stack().len();
+        if new_depth > depth {
+            self.enter(vm.pointer());
+        } else if new_depth < depth {
+            self.leave();
+        } else if let Instruction::TailCall(_) = instruction {
+            // A TailCall replaces the procedure on top of the stack
+            self.leave();
+            self.enter(vm.pointer());
+        } else if instruction.target().is_some() && vm.pointer() <= ip {
+            *self.back_edges.entry((ip, vm.pointer())).or_insert(0) += 1;
+        }
+
+        Ok(running)
+    }
+
+    fn enter(&mut self, target: Pointer) {
+        let proc = match self.entries.get(&target) {
+            Some(&proc) => proc,
+            None => {
+                // A call to somewhere that isn't a known procedure
+                let proc = self.names.len();
+                self.names.push(format!("ip_{}", target));
+                self.procs.push(ProcStats::default());
+                self.entries.insert(target, proc);
+                proc
+            }
+        };
+
+        let (parent, _) = *self.stack.last().expect("main is never popped");
+        let node = match self.nodes[parent].children.get(&proc) {
+            Some(&node) => node,
+            None => {
+                let node = self.nodes.len();
+                self.nodes.push(Node {
+                    proc,
+                    parent: Some(parent),
+                    children: BTreeMap::new(),
+                    count: 0,
+                });
+                self.nodes[parent].children.insert(proc, node);
+                node
+            }
+        };
+
+        let stats = &mut self.procs[proc];
+        stats.calls += 1;
+        stats.active += 1;
+        self.stack.push((node, self.total));
+    }
+
+    fn leave(&mut self) {
+        if self.stack.len() == 1 {
+            return;
+        }
+
+        let (node, start) = self.stack.pop().expect("checked above");
+        let stats = &mut self.procs[self.nodes[node].proc];
+        stats.active -= 1;
+        if stats.active == 0 {
+            stats.inclusive += self.total - start;
+        }
+    }
+
+    // finish closes off any procedures that never returned, and
+    // counts everything towards main. It's called once the program
+    // ends or faults.
+    fn finish(&mut self) {
+        while self.stack.len() > 1 {
+            self.leave();
+        }
+        self.procs[0].calls = 1;
+        self.procs[0].inclusive = self.total;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    // write_folded writes the call chains in the folded stacks format
+    // used by flamegraph tools, one chain per line:
+    //
+    // main;fib;fib 1234
+    pub fn write_folded(&self, out: &mut impl Write) -> std::io::Result<()> {
+        for (i, node) in self.nodes.iter().enumerate() {
+            if node.count == 0 {
+                continue;
+            }
+
+            let mut chain = Vec::new();
+            let mut current = Some(i);
+            while let Some(n) = current {
+                chain.push(self.names[self.nodes[n].proc].as_str());
+                current = self.nodes[n].parent;
+            }
+            chain.reverse();
+
+            writeln!(out, "{} {}", chain.join(";"), node.count)?;
+        }
+
+        Ok(())
+    }
+
+    // write_report writes a summary of the profile as a few tables.
+    pub fn write_report(&self, program: &Program, out: &mut impl Write) -> std::io::Result<()> {
+        let percent = |n: u64| 100.0 * n as f64 / self.total.max(1) as f64;
+
+        writeln!(out, "profile: {} instructions executed", self.total)?;
+
+        writeln!(out, "\n{:<12} {:>12} {:>7}", "opcode", "count", "%")?;
+        let mut opcodes = self.opcodes.iter().collect::<Vec<_>>();
+        opcodes.sort_by_key(|&(_, &count)| std::cmp::Reverse(count));
+        for (name, &count) in opcodes {
+            writeln!(out, "{:<12} {:>12} {:>6.2}%", name, count, percent(count))?;
+        }
+
+        writeln!(
+            out,
+            "\n{:<16} {:>10} {:>12} {:>7} {:>12} {:>7}",
+            "procedure", "calls", "inclusive", "%", "exclusive", "%"
+        )?;
+        let mut procs = self.procs.iter().enumerate().collect::<Vec<_>>();
+        procs.sort_by_key(|&(_, stats)| std::cmp::Reverse(stats.exclusive));
+        for (i, stats) in procs.into_iter().filter(|(_, s)| s.calls > 0) {
+            writeln!(
+                out,
+                "{:<16} {:>10} {:>12} {:>6.2}% {:>12} {:>6.2}%",
+                self.names[i],
+                stats.calls,
+                stats.inclusive,
+                percent(stats.inclusive),
+                stats.exclusive,
+                percent(stats.exclusive)
+            )?;
+        }
+
+        let line = |ip: Pointer| {
+            program
+                .span(ip)
+                .map_or_else(|| "-".to_string(), |s| s.line.to_string())
+        };
+
+        writeln!(
+            out,
+            "\n{:>6} {:>6} {:>12} {:>7}  instruction",
+            "ip", "line", "count", "%"
+        )?;
+        let mut ips = self.ips.iter().enumerate().collect::<Vec<_>>();
+        ips.sort_by_key(|&(_, &count)| std::cmp::Reverse(count));
+        for (ip, &count) in ips.into_iter().take(10).filter(|(_, &c)| c > 0) {
+            writeln!(
+                out,
+                "{:>6} {:>6} {:>12} {:>6.2}%  {}",
+                ip,
+                line(ip),
+                count,
+                percent(count),
+                program.instructions[ip]
+            )?;
+        }
+
+        if self.back_edges.is_empty() {
+            return Ok(());
+        }
+
+        // A loop is a backwards jump, and the number of times it's taken
+        // is the number of iterations.
+        writeln!(
+            out,
+            "\n{:<16} {:>12} {:>12} {:>12}",
+            "loop", "lines", "iterations", "body size"
+        )?;
+        let mut loops = self.back_edges.iter().collect::<Vec<_>>();
+        loops.sort_by_key(|&(_, &count)| std::cmp::Reverse(count));
+        for (&(from, to), &count) in loops.into_iter().take(10) {
+            let name = program
+                .labels
+                .iter()
+                .find(|(_, &ip)| ip == to)
+                .map_or_else(|| format!("ip {}", to), |(name, _)| name.clone());
+            writeln!(
+                out,
+                "{:<16} {:>12} {:>12} {:>12}",
+                name,
+                format!("{}-{}", line(to), line(from)),
+                count,
+                from + 1 - to
+            )?;
+        }
+
+        Ok(())
+    }
+}
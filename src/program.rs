@@ -0,0 +1,107 @@
+use std::collections::BTreeMap;
+
+use crate::asm::Span;
+use crate::heap::Heap;
+use crate::instruction::{Instruction, Pointer};
+use crate::value::Value;
+
+// A procedure has a name, a start instruction pointer,
+// and an end instruction pointer.
+// The ending instruction pointer is just used to skip over
+// the procedure.
+pub type Procedures = BTreeMap<String, (Pointer, Pointer)>;
+
+// A Signature is the optional part of a procedure declaration which
+// says how many arguments it takes, and how many results it leaves in
+// their place.
+//
+// Example: `Proc ackermann 2 -> 1`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Signature {
+    pub args: usize,
+    pub results: usize,
+}
+
+pub type Signatures = BTreeMap<String, Signature>;
+
+// A native function is declared with a name and a Signature, so that
+// the assembler knows `Call name` isn't a typo and the verifier knows
+// what it does to the stack.
+//
+// Example: `.native sqrt 1 -> 1`
+pub type Natives = Vec<(String, Signature)>;
+
+// A Label is a name and an instruction pointer
+pub type Labels = BTreeMap<String, Pointer>;
+
+// A Constant is a named block of data, and the Str or Array
+// pointer which PushAddr pushes for it.
+pub type Constants = BTreeMap<String, Value>;
+
+// A Program is what the Assembler produces and what the Vm runs.
+//
+// The VM itself only needs the instructions, but the names of labels
+// and procedures, along with where each instruction came from in the
+// source, are kept around for tools built on top of it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Program {
+    pub instructions: Vec<Instruction>,
+    pub labels: Labels,
+    pub procedures: Procedures,
+    // The signatures of the procedures which declare one
+    pub signatures: Signatures,
+
+    // spans[ip] is the location of instruction ip in the source file.
+    // It's empty if the Program wasn't assembled from source.
+    pub spans: Vec<Span>,
+
+    // Constant blocks, like string literals, which are copied into the
+    // Heap in order before the Program starts. Since a fresh Heap always
+    // allocates the same way, their pointers are known ahead of time;
+    // see data_pointers.
+    pub data: Vec<Vec<Value>>,
+
+    // The names of the blocks in data which were declared in the
+    // `.data` section.
+    pub constants: Constants,
+
+    // The native functions the program declares, which the host
+    // registers with Vm::register_native. CallNative(i) calls
+    // natives[i].
+    pub natives: Natives,
+}
+
+impl Program {
+    // Creates a Program out of bare instructions, with no names or spans.
+    pub fn new(instructions: Vec<Instruction>) -> Self {
+        Program {
+            instructions,
+            ..Program::default()
+        }
+    }
+
+    pub fn span(&self, ip: Pointer) -> Option<Span> {
+        self.spans.get(ip).copied()
+    }
+
+    // data_pointers returns where each data block will be in the Heap.
+    pub fn data_pointers(&self) -> Vec<isize> {
+        let mut heap = Heap::new();
+        self.data.iter().map(|d| heap.alloc_values(d)).collect()
+    }
+
+    // procedure_at returns the name of the procedure whose body
+    // contains ip, if there is one.
+    pub fn procedure_at(&self, ip: Pointer) -> Option<&str> {
+        self.procedures
+            .iter()
+            .filter(|(_, &(start, end))| start < ip && ip < end)
+            .min_by_key(|(_, &(start, end))| end - start)
+            .map(|(name, _)| name.as_str())
+    }
+
+    // ip_at_line returns the first instruction on or after a source line.
+    pub fn ip_at_line(&self, line: usize) -> Option<Pointer> {
+        self.spans.iter().position(|s| s.line >= line)
+    }
+}
@@ -0,0 +1,211 @@
+use std::collections::BTreeMap;
+
+use crate::bytecode::{write_uleb, write_value, DecodeError, Reader};
+use crate::heap::Heap;
+use crate::object;
+use crate::program::Program;
+use crate::vm::{CallStack, Stack, StackFrame, Vm, VmConfig};
+
+// A snapshot stores the state of a running Vm, so that it can be saved
+// to a file and resumed later, possibly by another process.
+//
+// The Program itself isn't stored, only a hash of it. Restoring takes
+// the Program to resume, and refuses it if its hash doesn't match,
+// since the stored instruction pointers would point into the wrong code.
+//
+// All numbers are unsigned LEB128, like in object files (see object.rs).
+//
+// magic        b"TVMS"
+// version      1 byte, currently 1
+// program      the 64 bit hash of the Program, as 8 little endian bytes
+//
+// pointer      the instruction pointer
+// stack        count, then that many tagged Values
+// call stack   count, then for each StackFrame:
+//                  stack offset, ip
+// heap         count, then that many tagged Values, one per word
+// blocks       count, then for each live block:
+//                  start, length, capacity
+// free         count, then for each free space:
+//                  start, capacity
+//
+// The VmConfig isn't stored, since limits are up to whoever resumes the
+// program, and they count from when it's resumed. Neither are the
+// program's arguments or the files it has open, which belong to the
+// process it was running in.
+
+pub const MAGIC: &[u8; 4] = b"TVMS";
+pub const VERSION: u8 = 1;
+
+// The ways restoring a snapshot can fail
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnapshotError {
+    Decode(DecodeError),
+    // The snapshot was taken while running a different Program
+    WrongProgram { expected: u64, found: u64 },
+    // The state doesn't hang together, like a heap block which runs
+    // past the end of the heap
+    Corrupt(&'static str),
+}
+
+impl std::fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotError::Decode(DecodeError::BadMagic) => write!(f, "not a tinyvm snapshot"),
+            SnapshotError::Decode(DecodeError::UnsupportedVersion(v)) => {
+                write!(f, "unsupported snapshot version {}", v)
+            }
+            SnapshotError::Decode(e) => write!(f, "{}", e),
+            SnapshotError::WrongProgram { expected, found } => write!(
+                f,
+                "the snapshot was taken from a different program (hash {:016x}, not {:016x})",
+                found, expected
+            ),
+            SnapshotError::Corrupt(what) => write!(f, "corrupt snapshot: {}", what),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<DecodeError> for SnapshotError {
+    fn from(e: DecodeError) -> Self {
+        SnapshotError::Decode(e)
+    }
+}
+
+// is_snapshot checks whether some bytes look like a snapshot.
+pub fn is_snapshot(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+// program_hash hashes a Program's object file encoding with 64 bit
+// FNV-1a. It isn't cryptographic, it's just there to catch a snapshot
+// being resumed with the wrong file.
+pub fn program_hash(program: &Program) -> u64 {
+    object::write(program)
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+        })
+}
+
+pub fn write(vm: &Vm) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    out.push(VERSION);
+    out.extend_from_slice(&program_hash(vm.program()).to_le_bytes());
+
+    write_uleb(&mut out, vm.pointer as u64);
+
+    write_uleb(&mut out, vm.stack.0.len() as u64);
+    for &v in vm.stack.0.iter() {
+        write_value(&mut out, v);
+    }
+
+    write_uleb(&mut out, vm.call_stack.len() as u64);
+    for frame in vm.call_stack.iter() {
+        write_uleb(&mut out, frame.stack_offset as u64);
+        write_uleb(&mut out, frame.ip as u64);
+    }
+
+    let heap = &vm.heap;
+    write_uleb(&mut out, heap.memory.len() as u64);
+    for &v in heap.memory.iter() {
+        write_value(&mut out, v);
+    }
+
+    write_uleb(&mut out, heap.blocks.len() as u64);
+    for (&start, &(len, capacity)) in heap.blocks.iter() {
+        write_uleb(&mut out, start as u64);
+        write_uleb(&mut out, len as u64);
+        write_uleb(&mut out, capacity as u64);
+    }
+
+    write_uleb(&mut out, heap.free.len() as u64);
+    for (&start, &capacity) in heap.free.iter() {
+        write_uleb(&mut out, start as u64);
+        write_uleb(&mut out, capacity as u64);
+    }
+
+    out
+}
+
+// read restores a Vm from a snapshot of program, with a fresh config.
+pub fn read(program: Program, config: VmConfig, bytes: &[u8]) -> Result<Vm, SnapshotError> {
+    let mut r = Reader::new(bytes);
+
+    if r.bytes(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+        return Err(DecodeError::BadMagic.into());
+    }
+
+    let version = r.byte()?;
+    if version != VERSION {
+        return Err(DecodeError::UnsupportedVersion(version).into());
+    }
+
+    let mut hash = [0; 8];
+    hash.copy_from_slice(r.bytes(8)?);
+    let found = u64::from_le_bytes(hash);
+    let expected = program_hash(&program);
+    if found != expected {
+        return Err(SnapshotError::WrongProgram { expected, found });
+    }
+
+    let pointer = r.usize()?;
+
+    let stack = (0..r.usize()?)
+        .map(|_| r.value())
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut call_stack = CallStack::new();
+    for _ in 0..r.usize()? {
+        let (stack_offset, ip) = (r.usize()?, r.usize()?);
+        if stack_offset > stack.len() {
+            return Err(SnapshotError::Corrupt("a stack frame is above the stack"));
+        }
+        call_stack.push(StackFrame { stack_offset, ip });
+    }
+
+    let memory = (0..r.usize()?)
+        .map(|_| r.value())
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Every block has to fit in the heap, and the word at 0 is null
+    let fits = |start: usize, capacity: usize| {
+        start > 0 && capacity > 0 && start.saturating_add(capacity) <= memory.len()
+    };
+
+    let mut blocks = BTreeMap::new();
+    for _ in 0..r.usize()? {
+        let (start, len, capacity) = (r.usize()?, r.usize()?, r.usize()?);
+        if !fits(start, capacity) || len > capacity {
+            return Err(SnapshotError::Corrupt("a heap block is out of bounds"));
+        }
+        blocks.insert(start, (len, capacity));
+    }
+
+    let mut free = BTreeMap::new();
+    for _ in 0..r.usize()? {
+        let (start, capacity) = (r.usize()?, r.usize()?);
+        if !fits(start, capacity) {
+            return Err(SnapshotError::Corrupt("a free space is out of bounds"));
+        }
+        free.insert(start, capacity);
+    }
+
+    if !r.is_empty() {
+        return Err(SnapshotError::Corrupt("unexpected bytes after the heap"));
+    }
+
+    let mut vm = Vm::with_config(program, config);
+    vm.pointer = pointer;
+    vm.stack = Stack(stack);
+    vm.call_stack = call_stack;
+    vm.heap = Heap {
+        memory,
+        blocks,
+        free,
+    };
+    Ok(vm)
+}
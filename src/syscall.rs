@@ -0,0 +1,339 @@
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+
+use crate::value::Value;
+use crate::vm::{check_heap, flush, Vm, VmErrorKind};
+
+// `Syscall n` gives programs access to the world outside of the Vm,
+// through a fixed table of operations. Like a procedure with a
+// Signature, each one pops its arguments and pushes its results:
+//
+// n  name   capability  before             after
+// 0  open   files       [.., path, mode]   [.., fd, true] or [.., 0, false]
+// 1  read   files       [.., fd]           [.., line, true] or [.., 0, false]
+// 2  write  files       [.., fd, str]      [..]
+// 3  close  files       [.., fd]           [..]
+// 4  clock  clock       [..]               [.., nanoseconds]
+// 5  exit   exit        [.., status]       stops the program
+// 6  argc   args        [..]               [.., count]
+// 7  arg    args        [.., i]            [.., str, true] or [.., 0, false]
+// 8  env    env         [.., name]         [.., str, true] or [.., 0, false]
+//
+// Like ReadInt, the operations which can come up empty push a bool
+// after their result, so the failure can be checked for with JE:
+//
+// - open fails if the file can't be opened. The mode is 0 to read,
+//   1 to write over the file, or 2 to append to it.
+// - read fails at the end of the file. Files are read a line at a
+//   time, and each line keeps its newline.
+// - arg fails if there's no argument i.
+// - env fails if the variable isn't set.
+//
+// Files are numbered like Unix file descriptors: 0 is the Vm's input,
+// 1 is its output and 2 is its diagnostics (see io.rs), and opened
+// files count up from 3. Files are text, so strings are written out as
+// UTF-8 like PrintS prints them.
+//
+// The clock counts up from when the Vm was created, and argument 0 is
+// usually the name of the program, like in C.
+//
+// Each operation needs its capability to be in the VmConfig's
+// Capabilities, or it faults without doing anything.
+
+// The kinds of access a Syscall can need
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    Files,
+    Clock,
+    Exit,
+    Args,
+    Env,
+}
+
+impl std::fmt::Display for Capability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Capability::Files => "files",
+            Capability::Clock => "clock",
+            Capability::Exit => "exit",
+            Capability::Args => "args",
+            Capability::Env => "env",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// Capabilities is the set of Capability a Vm is allowed to use.
+//
+// The default is SANDBOXED, which keeps the program away from the
+// host's files and environment. A host which trusts its programs can
+// hand out everything:
+//
+// let config = VmConfig {
+//     capabilities: Capabilities::TRUSTED,
+//     ..VmConfig::default()
+// };
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    pub files: bool,
+    pub clock: bool,
+    pub exit: bool,
+    pub args: bool,
+    pub env: bool,
+}
+
+impl Capabilities {
+    pub const NONE: Capabilities = Capabilities {
+        files: false,
+        clock: false,
+        exit: false,
+        args: false,
+        env: false,
+    };
+
+    pub const SANDBOXED: Capabilities = Capabilities {
+        clock: true,
+        exit: true,
+        args: true,
+        ..Capabilities::NONE
+    };
+
+    pub const TRUSTED: Capabilities = Capabilities {
+        files: true,
+        clock: true,
+        exit: true,
+        args: true,
+        env: true,
+    };
+
+    pub fn allows(&self, capability: Capability) -> bool {
+        match capability {
+            Capability::Files => self.files,
+            Capability::Clock => self.clock,
+            Capability::Exit => self.exit,
+            Capability::Args => self.args,
+            Capability::Env => self.env,
+        }
+    }
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Capabilities::SANDBOXED
+    }
+}
+
+// An entry in the Syscall table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Syscall {
+    pub name: &'static str,
+    pub capability: Capability,
+    // How many values it pops, and how many it pushes
+    pub args: usize,
+    pub results: usize,
+}
+
+pub const OPEN: usize = 0;
+pub const READ: usize = 1;
+pub const WRITE: usize = 2;
+pub const CLOSE: usize = 3;
+pub const CLOCK: usize = 4;
+pub const EXIT: usize = 5;
+pub const ARGC: usize = 6;
+pub const ARG: usize = 7;
+pub const ENV: usize = 8;
+
+pub const TABLE: [Syscall; 9] = [
+    syscall("open", Capability::Files, 2, 2),
+    syscall("read", Capability::Files, 1, 2),
+    syscall("write", Capability::Files, 2, 0),
+    syscall("close", Capability::Files, 1, 0),
+    syscall("clock", Capability::Clock, 0, 1),
+    syscall("exit", Capability::Exit, 1, 0),
+    syscall("argc", Capability::Args, 0, 1),
+    syscall("arg", Capability::Args, 1, 2),
+    syscall("env", Capability::Env, 1, 2),
+];
+
+const fn syscall(
+    name: &'static str,
+    capability: Capability,
+    args: usize,
+    results: usize,
+) -> Syscall {
+    Syscall {
+        name,
+        capability,
+        args,
+        results,
+    }
+}
+
+// A file opened by `open`
+pub(crate) enum File {
+    Read(BufReader<fs::File>),
+    Write(BufWriter<fs::File>),
+}
+
+// The first descriptor handed out to an opened file
+const FIRST_FILE: isize = 3;
+
+// call runs Syscall n on the Vm.
+pub(crate) fn call(vm: &mut Vm, n: usize) -> Result<(), VmErrorKind> {
+    let syscall = TABLE.get(n).ok_or(VmErrorKind::UnknownSyscall(n))?;
+    if !vm.config.capabilities.allows(syscall.capability) {
+        return Err(VmErrorKind::Denied {
+            syscall: syscall.name,
+            capability: syscall.capability,
+        });
+    }
+
+    match n {
+        OPEN => {
+            let mode = vm.stack.pop_int()?;
+            let path = pop_str(vm)?;
+            let file = match mode {
+                0 => fs::File::open(&path).map(|f| File::Read(BufReader::new(f))),
+                1 => fs::File::create(&path).map(|f| File::Write(BufWriter::new(f))),
+                2 => fs::OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .open(&path)
+                    .map(|f| File::Write(BufWriter::new(f))),
+                _ => return Err(VmErrorKind::InvalidMode(mode)),
+            };
+
+            match file {
+                Ok(file) => {
+                    // Closed descriptors are reused, lowest first
+                    let slot = match vm.files.iter().position(Option::is_none) {
+                        Some(slot) => slot,
+                        None => {
+                            vm.files.push(None);
+                            vm.files.len() - 1
+                        }
+                    };
+                    vm.files[slot] = Some(file);
+                    push_found(vm, Some(Value::Int(FIRST_FILE + slot as isize)));
+                }
+                Err(_) => push_found(vm, None),
+            }
+        }
+        READ => {
+            let fd = vm.stack.pop_int()?;
+            let line = match fd {
+                0 => {
+                    let Vm {
+                        input,
+                        output,
+                        diagnostics,
+                        ..
+                    } = vm;
+                    input.read_line(&mut || flush(output, diagnostics))?
+                }
+                _ => match file(vm, fd)? {
+                    File::Read(f) => {
+                        let mut line = Vec::new();
+                        f.read_until(b'\n', &mut line)?;
+                        line
+                    }
+                    File::Write(_) => return Err(VmErrorKind::BadFile(fd)),
+                },
+            };
+
+            let line = if line.is_empty() {
+                None
+            } else {
+                Some(alloc_str(vm, &String::from_utf8_lossy(&line))?)
+            };
+            push_found(vm, line)
+        }
+        WRITE => {
+            let s = pop_str(vm)?;
+            let fd = vm.stack.pop_int()?;
+            match fd {
+                1 => vm.output.write_all(s.as_bytes())?,
+                2 => vm.diagnostics.write_all(s.as_bytes())?,
+                _ => match file(vm, fd)? {
+                    File::Write(f) => f.write_all(s.as_bytes())?,
+                    File::Read(_) => return Err(VmErrorKind::BadFile(fd)),
+                },
+            }
+        }
+        CLOSE => {
+            let fd = vm.stack.pop_int()?;
+            file(vm, fd)?;
+            if let Some(File::Write(mut f)) = vm.files[(fd - FIRST_FILE) as usize].take() {
+                f.flush()?;
+            }
+        }
+        CLOCK => {
+            let nanos = vm.created.elapsed().as_nanos();
+            vm.stack
+                .push(Value::Int(isize::try_from(nanos).unwrap_or(isize::MAX)))
+        }
+        EXIT => {
+            let status = vm.stack.pop_int()?;
+            vm.exit(status)?;
+        }
+        ARGC => vm.stack.push(Value::Int(vm.args.len() as isize)),
+        ARG => {
+            let i = vm.stack.pop_int()?;
+            let arg = usize::try_from(i)
+                .ok()
+                .and_then(|i| vm.args.get(i))
+                .cloned();
+            let arg = arg.map(|arg| alloc_str(vm, &arg)).transpose()?;
+            push_found(vm, arg)
+        }
+        ENV => {
+            let name = pop_str(vm)?;
+            let var = std::env::var(name).ok();
+            let var = var.map(|var| alloc_str(vm, &var)).transpose()?;
+            push_found(vm, var)
+        }
+        _ => unreachable!("every entry in the table is handled"),
+    }
+
+    Ok(())
+}
+
+// file looks up an opened file by its descriptor.
+fn file(vm: &mut Vm, fd: isize) -> Result<&mut File, VmErrorKind> {
+    fd.checked_sub(FIRST_FILE)
+        .and_then(|slot| usize::try_from(slot).ok())
+        .and_then(move |slot| vm.files.get_mut(slot))
+        .and_then(Option::as_mut)
+        .ok_or(VmErrorKind::BadFile(fd))
+}
+
+// push_found pushes a value and true, or 0 and false if there's no value.
+fn push_found(vm: &mut Vm, v: Option<Value>) {
+    vm.stack.push(v.unwrap_or(Value::Int(0)));
+    vm.stack.push(Value::Bool(v.is_some()));
+}
+
+// pop_str pops a string and reads it out of the Heap.
+fn pop_str(vm: &mut Vm) -> Result<String, VmErrorKind> {
+    match vm.stack.pop()? {
+        Value::Str(p) => vm
+            .heap
+            .block_values(p)?
+            .iter()
+            .map(|&c| match c {
+                Value::Int(c) => Ok(std::char::from_u32(c as u32).unwrap_or('\u{fffd}')),
+                v => Err(VmErrorKind::type_error("int", v)),
+            })
+            .collect(),
+        v => Err(VmErrorKind::type_error("str", v)),
+    }
+}
+
+// alloc_str copies a string into the Heap, one char per word, like the
+// string literals in the Program's data.
+fn alloc_str(vm: &mut Vm, s: &str) -> Result<Value, VmErrorKind> {
+    let chars: Vec<Value> = s.chars().map(|c| Value::Int(c as isize)).collect();
+    check_heap(&vm.config, &vm.heap, chars.len())?;
+    Ok(Value::Str(vm.heap.alloc_values(&chars)))
+}
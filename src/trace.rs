@@ -0,0 +1,152 @@
+use std::io::Write;
+
+use crate::value::Value;
+use crate::vm::{Vm, VmError};
+
+// A Tracer runs a Vm while writing one line per executed instruction,
+// showing where the instruction came from and the stack before and
+// after it ran.
+//
+// Text lines look like:
+//
+//    ip  line  proc          instruction       stack
+//    12     7  fib           GetArg 0          [5 |] -> [5 | 5]
+//
+// JSON lines hold the same information, along with the stack frame
+// offset instead of the pipe:
+//
+// {"ip":12,"line":7,"proc":"fib","op":"GetArg 0","frame":1,"before":[5],"after":[5,5]}
+//
+// Instructions outside of any procedure have a proc of `-` in text,
+// or null in JSON. Lines are null if the Program has no source spans.
+//
+// If an instruction faults, the after stack is replaced by the error.
+pub struct Tracer<W: Write> {
+    out: W,
+    format: TraceFormat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    Text,
+    Json,
+}
+
+impl<W: Write> Tracer<W> {
+    pub fn new(out: W, format: TraceFormat) -> Self {
+        Tracer { out, format }
+    }
+
+    // run runs the Vm to completion, tracing every instruction. Failing
+    // to write the trace stops the program; a fault in the program is
+    // traced and then returned as the inner error.
+    pub fn run(&mut self, vm: &mut Vm) -> std::io::Result<Result<(), VmError>> {
+        if self.format == TraceFormat::Text {
+            writeln!(
+                self.out,
+                "{:>5} {:>5}  {:<12}  {:<16}  stack",
+                "ip", "line", "proc", "instruction"
+            )?;
+        }
+
+        loop {
+            match self.step(vm)? {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => return Ok(Err(e)),
+            }
+        }
+
+        self.out.flush()?;
+        Ok(Ok(()))
+    }
+
+    // step runs and traces a single instruction, like Vm::step.
+    pub fn step(&mut self, vm: &mut Vm) -> std::io::Result<Result<bool, VmError>> {
+        let ip = vm.pointer();
+        let instruction = match vm.program().instructions.get(ip) {
+            Some(instruction) => instruction.to_string(),
+            None => return Ok(Ok(false)),
+        };
+
+        let line = vm.program().span(ip).map(|s| s.line);
+        let proc = vm.program().procedure_at(ip).map(str::to_string);
+        let frame = vm.frame_offset();
+        let before_text = vm.stack_string();
+        let before = vm.stack().to_vec();
+
+        let res = vm.step();
+
+        match self.format {
+            TraceFormat::Text => {
+                let after = match &res {
+                    Ok(_) => vm.stack_string(),
+                    Err(e) => format!("error: {}", e.kind),
+                };
+                writeln!(
+                    self.out,
+                    "{:>5} {:>5}  {:<12}  {:<16}  {} -> {}",
+                    ip,
+                    line.map_or_else(|| "-".to_string(), |l| l.to_string()),
+                    proc.as_deref().unwrap_or("-"),
+                    instruction,
+                    before_text,
+                    after
+                )?;
+            }
+            TraceFormat::Json => {
+                let after = match &res {
+                    Ok(_) => format!("\"after\":{}", json_array(vm.stack())),
+                    Err(e) => format!("\"error\":{}", json_string(&e.kind.to_string())),
+                };
+                writeln!(
+                    self.out,
+                    "{{\"ip\":{},\"line\":{},\"proc\":{},\"op\":{},\"frame\":{},\"before\":{},{}}}",
+                    ip,
+                    line.map_or_else(|| "null".to_string(), |l| l.to_string()),
+                    proc.as_deref()
+                        .map_or_else(|| "null".to_string(), json_string),
+                    json_string(&instruction),
+                    frame,
+                    json_array(&before),
+                    after
+                )?;
+            }
+        }
+
+        Ok(res)
+    }
+}
+
+fn json_array(values: &[Value]) -> String {
+    let values = values.iter().map(|&v| json_value(v)).collect::<Vec<_>>();
+    format!("[{}]", values.join(","))
+}
+
+// Ints, finite floats and bools are plain JSON values. Pointers are
+// objects like {"str":3}, so they can't be mistaken for ints, and
+// floats that JSON can't represent are strings like "NaN".
+fn json_value(v: Value) -> String {
+    match v {
+        Value::Int(i) => i.to_string(),
+        Value::Float(x) if x.is_finite() => format!("{:?}", x),
+        Value::Float(x) => json_string(&x.to_string()),
+        Value::Bool(b) => b.to_string(),
+        Value::Str(p) => format!("{{\"str\":{}}}", p),
+        Value::Array(p) => format!("{{\"array\":{}}}", p),
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut res = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
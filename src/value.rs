@@ -0,0 +1,49 @@
+// A Value is anything that can be on the Stack or in the Heap.
+//
+// Strings and arrays live in the Heap, so on the Stack they're just a
+// pointer tagged with what it points to. A string is a block of Int
+// character codes, and an array is a block of any Values.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Int(isize),
+    Float(f64),
+    Bool(bool),
+    Str(isize),
+    Array(isize),
+}
+
+impl Default for Value {
+    fn default() -> Self {
+        Value::Int(0)
+    }
+}
+
+impl Value {
+    // type_name returns the name of the Value's type, for error messages.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Int(_) => "int",
+            Value::Float(_) => "float",
+            Value::Bool(_) => "bool",
+            Value::Str(_) => "str",
+            Value::Array(_) => "array",
+        }
+    }
+}
+
+// Values are displayed without looking into the Heap, so strings and
+// arrays just show their pointer.
+//
+// Example: `3`, `3.14`, `true`, `str@5`, `array@12`
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Int(i) => write!(f, "{}", i),
+            // Debug formatting keeps the `.0` on whole floats
+            Value::Float(x) => write!(f, "{:?}", x),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Str(p) => write!(f, "str@{}", p),
+            Value::Array(p) => write!(f, "array@{}", p),
+        }
+    }
+}
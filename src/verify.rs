@@ -0,0 +1,527 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+use crate::asm::Span;
+use crate::instruction::{Instruction, Pointer};
+use crate::program::{Program, Signature};
+use crate::syscall;
+
+// The verifier checks that a Program keeps its stack balanced, without
+// running it. It follows every path through the code, keeping track of
+// how high the stack is before each instruction, and reports:
+//
+// - instructions which would pop or peek an empty stack
+// - labels which are reached with different stack heights, like a loop
+//   which pushes a value every time around
+// - Get and Set indexes outside of the stack frame
+// - GetArg, SetArg and Ret outside of a procedure
+// - procedures which return with different stack heights, or which
+//   reach their End without returning
+// - `Ret n` with fewer than n values in the frame
+//
+// Procedures are allowed to use values below their frame; that's how
+// addMul in procedure.bytecode works, and it's what GetArg does. Each
+// procedure is summarized by how many values it needs below its frame,
+// and by how much it changes the height of the stack. Calls use the
+// summary of the procedure they call, so a procedure is checked once
+// rather than at every call site.
+//
+// Procedures can call each other recursively, so the summaries are
+// worked out together: every procedure is analyzed over and over with
+// the latest summaries of the others, until none of them change. A
+// procedure which hasn't been seen to return yet doesn't return as far
+// as its callers know, which is what lets `factorial` be summarized by
+// its base case first.
+//
+// Native functions aren't registered until the program runs, but they
+// have to be declared with a Signature, so a call to one is checked like
+// a call to a procedure with that Signature.
+//
+// Example:
+//
+// Proc factorial   -- needs 1, changes the height by 0
+//     JE retOne    -- peeks the argument, below the frame
+//     ...
+// End
+
+// A VerifyError is a problem at a single instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyError {
+    pub ip: Pointer,
+    // Where the instruction came from, if the Program has spans
+    pub span: Option<Span>,
+    pub message: String,
+}
+
+impl std::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.span {
+            Some(span) => write!(
+                f,
+                "{} at instruction {} (line {})",
+                self.message, self.ip, span.line
+            ),
+            None => write!(f, "{} at instruction {}", self.message, self.ip),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+// What the callers of a procedure need to know about it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+struct Summary {
+    // How many values below its frame it uses
+    need: usize,
+    // How much it changes the height of the stack by, or None if it
+    // hasn't been seen to return
+    delta: Option<isize>,
+}
+
+impl From<Signature> for Summary {
+    fn from(sig: Signature) -> Self {
+        Summary {
+            need: sig.args,
+            delta: Some(sig.results as isize - sig.args as isize),
+        }
+    }
+}
+
+// The code being analyzed: either the top level of the program, or
+// the body of a procedure.
+#[derive(Debug, Clone, Copy)]
+struct Context<'a> {
+    entry: Pointer,
+    // None at the top level
+    name: Option<&'a str>,
+    // The ip of the procedure's End, if it's known
+    end: Option<Pointer>,
+}
+
+pub fn verify(program: &Program) -> Result<(), Vec<VerifyError>> {
+    let instructions = &program.instructions;
+
+    // Every called instruction is a procedure. Most have a name in the
+    // symbol table, and the rest are named after their ip.
+    let mut procs: BTreeMap<Pointer, Context> = BTreeMap::new();
+    for (name, &(start, end)) in program.procedures.iter() {
+        let context = Context {
+            entry: start + 1,
+            name: Some(name.as_str()),
+            end: Some(end - 1),
+        };
+        procs.insert(start + 1, context);
+    }
+
+    let unnamed: Vec<(Pointer, String)> = instructions
+        .iter()
+        .filter_map(|i| match *i {
+            Instruction::Call(p) | Instruction::TailCall(p) if !procs.contains_key(&p) => {
+                Some((p, format!("ip_{}", p)))
+            }
+            _ => None,
+        })
+        .collect();
+    for (entry, name) in unnamed.iter() {
+        procs.entry(*entry).or_insert(Context {
+            entry: *entry,
+            name: Some(name.as_str()),
+            end: None,
+        });
+    }
+
+    // Procedures with a Signature are already summarized by it
+    let mut summaries: BTreeMap<Pointer, Summary> = procs
+        .iter()
+        .map(|(&p, context)| {
+            let summary = context
+                .name
+                .and_then(|name| program.signatures.get(name))
+                .map_or_else(Summary::default, |&sig| Summary::from(sig));
+            (p, summary)
+        })
+        .collect();
+
+    // Each round can only grow a summary, but a procedure which keeps
+    // needing more values below its frame every time it recurses would
+    // grow forever, so the number of rounds is capped.
+    let mut errors = Vec::new();
+    let mut rounds = 0;
+    loop {
+        let mut changed = Vec::new();
+        for (&entry, context) in procs.iter() {
+            let (summary, _) = analyze(program, *context, &summaries);
+            if summaries[&entry] != summary {
+                summaries.insert(entry, summary);
+                changed.push(entry);
+            }
+        }
+
+        if changed.is_empty() {
+            break;
+        }
+
+        rounds += 1;
+        if rounds > instructions.len() + 1 {
+            for entry in changed {
+                errors.push(error(
+                    program,
+                    entry,
+                    format!(
+                        "`{}` uses more of the stack every time it recurses",
+                        procs[&entry].name.unwrap_or("main")
+                    ),
+                ));
+            }
+            break;
+        }
+    }
+
+    let top = Context {
+        entry: 0,
+        name: None,
+        end: None,
+    };
+    for context in std::iter::once(&top).chain(procs.values()) {
+        let (_, context_errors) = analyze(program, *context, &summaries);
+        errors.extend(context_errors);
+    }
+
+    if errors.is_empty() {
+        return Ok(());
+    }
+
+    errors.sort_by_key(|e| e.ip);
+    errors.dedup();
+    Err(errors)
+}
+
+fn error(program: &Program, ip: Pointer, message: String) -> VerifyError {
+    VerifyError {
+        ip,
+        span: program.span(ip),
+        message,
+    }
+}
+
+// effect returns how many values an instruction reads off the top of
+// the stack, and how many it leaves in their place. Jumps, calls and
+// returns are handled separately.
+fn effect(instruction: Instruction) -> (usize, usize) {
+    use Instruction::*;
+
+    match instruction {
+        Push(_) | Get(_) | GetArg(_) | ReadC => (0, 1),
+        ReadInt => (0, 2),
+        Enter(n) => (0, n),
+        Pop | SetPop(_) | Free | Exit => (1, 0),
+        Add | Sub | Mul | Div | Load => (2, 1),
+        Incr | Decr | Set(_) | SetArg(_) | Print | PrintC | PrintS | Alloc | Halt => (1, 1),
+        Store => (3, 0),
+        Noop
+        | PrintStack
+        | Jump(_)
+        | Call(_)
+        | TailCall(_)
+        | CallNative(_)
+        | Ret
+        | Return(_, _)
+        | RetN(_, _) => (0, 0),
+        JE(_) | JNE(_) | JGT(_) | JLT(_) | JGE(_) | JLE(_) => (1, 1),
+        Syscall(n) => syscall::TABLE
+            .get(n)
+            .map_or((0, 0), |s| (s.args, s.results)),
+    }
+}
+
+// analyze follows every path from the entry of a context, returning its
+// summary and any errors found along the way.
+fn analyze(
+    program: &Program,
+    context: Context,
+    summaries: &BTreeMap<Pointer, Summary>,
+) -> (Summary, Vec<VerifyError>) {
+    let mut analysis = Analysis {
+        program,
+        context,
+        summaries,
+        top_level: context.name.is_none(),
+        heights: vec![None; program.instructions.len()],
+        work: Vec::new(),
+        summary: Summary::default(),
+        errors: Vec::new(),
+    };
+
+    analysis.reach(context.entry, 0);
+    while let Some((ip, h)) = analysis.work.pop() {
+        analysis.step(ip, h);
+    }
+
+    (analysis.summary, analysis.errors)
+}
+
+// An Analysis is the state of analyze.
+//
+// Heights are relative to the frame, so they start at 0 and go negative
+// when a procedure uses values below its frame. At the top level there's
+// nothing below the frame, so going negative is an underflow.
+struct Analysis<'a> {
+    program: &'a Program,
+    context: Context<'a>,
+    summaries: &'a BTreeMap<Pointer, Summary>,
+    top_level: bool,
+    // The height of the stack before each instruction, once it's reached
+    heights: Vec<Option<isize>>,
+    // Instructions which have been reached but not stepped over yet
+    work: Vec<(Pointer, isize)>,
+    summary: Summary,
+    errors: Vec<VerifyError>,
+}
+
+impl<'a> Analysis<'a> {
+    fn error(&mut self, ip: Pointer, message: String) {
+        self.errors.push(error(self.program, ip, message));
+    }
+
+    fn name(&self) -> &'a str {
+        self.context.name.unwrap_or("main")
+    }
+
+    // reach records that ip can run with the stack at height h
+    fn reach(&mut self, ip: Pointer, h: isize) {
+        match self.heights.get(ip).copied() {
+            // Running off the end of the program stops it
+            None => {}
+            Some(None) => {
+                self.heights[ip] = Some(h);
+                self.work.push((ip, h));
+            }
+            Some(Some(old)) if old != h => {
+                let message = format!(
+                    "the stack is {} deep here on one path but {} on another",
+                    self.describe(old),
+                    self.describe(h)
+                );
+                self.error(ip, message);
+            }
+            Some(Some(_)) => {}
+        }
+    }
+
+    // read checks that there are at least k values on a stack of height
+    // h, returning false if there aren't. In a procedure, reading below
+    // the frame means it needs that many values from its caller.
+    fn read(&mut self, ip: Pointer, k: usize, h: isize) -> bool {
+        let below = k as isize - h;
+        if below <= 0 {
+            return true;
+        }
+
+        if self.top_level {
+            let message = format!(
+                "stack underflow: `{}` needs {} value{} but the stack has {}",
+                self.program.instructions[ip],
+                k,
+                plural(k),
+                h
+            );
+            self.error(ip, message);
+            return false;
+        }
+
+        self.summary.need = self.summary.need.max(below as usize);
+        true
+    }
+
+    fn step(&mut self, ip: Pointer, h: isize) {
+        use Instruction::*;
+
+        let instruction = self.program.instructions[ip];
+
+        if Some(ip) == self.context.end {
+            let message = format!("`{}` reaches its `End` without returning", self.name());
+            self.error(ip, message);
+            return;
+        }
+
+        // After an underflow, carry on as if the values had been there,
+        // so that the rest of the program still gets checked
+        let (pops, pushes) = effect(instruction);
+        let h = if self.read(ip, pops, h) {
+            h
+        } else {
+            pops as isize
+        };
+
+        match instruction {
+            // Set and SetPop write the index before anything is popped. The
+            // height is compared as a usize, since a huge index is negative
+            // as an isize
+            Get(i) | Set(i) | SetPop(i) if i >= h.max(0) as usize => {
+                let message = format!(
+                    "`{}` is outside of the stack frame, which holds {} value{}",
+                    instruction,
+                    h.max(0),
+                    plural(h.max(0) as usize)
+                );
+                self.error(ip, message);
+            }
+            GetArg(_) | SetArg(_) if self.top_level => {
+                let message = format!("`{}` is outside of a procedure", instruction);
+                self.error(ip, message);
+            }
+            GetArg(i) | SetArg(i) => self.summary.need = self.summary.need.max(i + 1),
+            _ => {}
+        }
+
+        match instruction {
+            Jump(p) => self.reach(p, h),
+            JE(p) | JNE(p) | JGT(p) | JLT(p) | JGE(p) | JLE(p) => {
+                // The value is only popped if the jump is taken
+                self.reach(p, h - 1);
+                self.reach(ip + 1, h);
+            }
+            TailCall(_) if self.top_level => {
+                self.error(ip, "`TailCall` is outside of a procedure".to_string());
+            }
+            CallNative(i) if i >= self.program.natives.len() => {
+                self.error(ip, format!("there's no native {}", i));
+            }
+            Call(p) | TailCall(p) | CallNative(p) => {
+                let callee = match instruction {
+                    CallNative(i) => Summary::from(self.program.natives[i].1),
+                    _ => self.summaries.get(&p).copied().unwrap_or_default(),
+                };
+                let need = callee.need as isize;
+                if self.top_level && need > h {
+                    let message = format!(
+                        "`Call` needs {} value{} on the stack but there {} {}",
+                        need,
+                        plural(callee.need),
+                        if h == 1 { "is" } else { "are" },
+                        h
+                    );
+                    self.error(ip, message);
+                }
+                self.summary.need = self.summary.need.max((need - h).max(0) as usize);
+
+                // A procedure which never returns doesn't come back here,
+                // and one called by a TailCall returns for this one
+                match (instruction, callee.delta.and_then(|d| self.grow(ip, h, d))) {
+                    (TailCall(_), Some(h)) => self.returns(ip, h),
+                    (_, Some(h)) => self.reach(ip + 1, h),
+                    (_, None) => {}
+                }
+            }
+            // These stop the program, so nothing after them runs
+            Halt | Exit | Syscall(syscall::EXIT) => {}
+            Syscall(n) if n >= syscall::TABLE.len() => {
+                self.error(ip, format!("there's no syscall {}", n));
+            }
+            Ret | Return(_, _) | RetN(_, _) if self.top_level => {
+                self.error(ip, "`Ret` is outside of a procedure".to_string());
+            }
+            Ret | Return(_, _) | RetN(_, _) => {
+                // A Return drops the arguments after checking the results,
+                // and a RetN drops them along with everything it doesn't keep
+                let delta = match instruction {
+                    Return(args, results) => {
+                        if h != results as isize {
+                            let message = format!(
+                                "`{}` returns {} value{} here, but its signature says {}",
+                                self.name(),
+                                h,
+                                plural(h.max(0) as usize),
+                                results
+                            );
+                            self.error(ip, message);
+                        }
+                        self.summary.need = self.summary.need.max(args);
+                        results as isize - args as isize
+                    }
+                    RetN(args, keep) => {
+                        if keep > h.max(0) as usize {
+                            let message = format!(
+                                "`Ret {}` keeps {} value{} but the frame only holds {}",
+                                keep,
+                                keep,
+                                plural(keep),
+                                h.max(0)
+                            );
+                            self.error(ip, message);
+                        }
+                        self.summary.need = self.summary.need.max(args);
+                        isize::try_from(keep).unwrap_or(isize::MAX) - args as isize
+                    }
+                    _ => h,
+                };
+                self.returns(ip, delta);
+            }
+            // A huge Enter can't be counted, let alone run
+            Enter(n) => match isize::try_from(n).ok().and_then(|n| h.checked_add(n)) {
+                Some(h) => self.reach(ip + 1, h),
+                None => {
+                    let message = format!(
+                        "`{}` asks for more locals than the stack can hold",
+                        instruction
+                    );
+                    self.error(ip, message);
+                }
+            },
+            _ => {
+                if let Some(h) = self.grow(ip, h - pops as isize, pushes as isize) {
+                    self.reach(ip + 1, h);
+                }
+            }
+        }
+    }
+
+    // grow adds delta to the height h, reporting an error instead if the
+    // new height is too large to count
+    fn grow(&mut self, ip: Pointer, h: isize, delta: isize) -> Option<isize> {
+        let grown = h.checked_add(delta);
+        if grown.is_none() {
+            let message = format!(
+                "`{}` leaves more values than the stack can hold",
+                self.program.instructions[ip]
+            );
+            self.error(ip, message);
+        }
+        grown
+    }
+
+    // returns records that the procedure returns here, having changed
+    // the height of the stack by delta
+    fn returns(&mut self, ip: Pointer, delta: isize) {
+        match self.summary.delta {
+            None => self.summary.delta = Some(delta),
+            Some(old) if old != delta => {
+                let message = format!(
+                    "`{}` changes the stack by {:+} here, but by {:+} at another `Ret`",
+                    self.name(),
+                    delta,
+                    old
+                );
+                self.error(ip, message);
+            }
+            Some(_) => {}
+        }
+    }
+
+    // describe formats a stack height for a message
+    fn describe(&self, h: isize) -> String {
+        match h {
+            _ if self.top_level => h.to_string(),
+            h if h < 0 => format!("{} below the frame", -h),
+            h => format!("{} above the frame", h),
+        }
+    }
+}
+
+fn plural(n: usize) -> &'static str {
+    if n == 1 {
+        ""
+    } else {
+        "s"
+    }
+}
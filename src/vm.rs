@@ -0,0 +1,1409 @@
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use crate::heap::{Heap, HeapError};
+use crate::instruction::{Instruction, Pointer};
+use crate::io::{self, Input, Sink, Source};
+use crate::program::Program;
+use crate::syscall::{self, Capabilities, Capability, File};
+use crate::value::Value;
+
+// A StackFrame has an offset and an instruction pointer
+// to return to.
+// The offset is used for the Get/Set and GetArg/SetArg instructions.
+//
+// Example:
+// With stack [1, 3, 2] on instruction 24, call a procedure
+// at location 96.
+//
+// The stack frame to be pushed will look like:
+// StackFrame {
+//      stack_offset: 3, // the length of the stack before calling the procedure
+//      ip: i,
+// }
+//
+// The current instruction pointer will be set to 96.
+//
+// Inside the procedure, let's say a few values are pushed,
+// resulting in a stack [1, 3, 2, 5, 7, 4].
+//
+// Stack values are now accessed by Get/Set and GetArg/SetArg
+// relatively to the stack offset (denoted by the pipe '|'):
+//
+//       GetArg 2    GetArg 1    GetArg 0     Get 0       Get 1       Get 2
+// [        1,          3,          2,    |     5,          7,          4       ]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackFrame {
+    pub stack_offset: Pointer,
+    pub ip: Pointer,
+}
+
+// The CallStack is just a Vec of StackFrames.
+pub type CallStack = Vec<StackFrame>;
+
+// The Stack is just a Vec of Values.
+//
+// The wrapper type turns every access that would otherwise panic
+// into a VmErrorKind, so that a faulty program can't take down the
+// host process.
+#[derive(Debug, Default)]
+pub(crate) struct Stack(pub(crate) Vec<Value>);
+
+impl Stack {
+    pub(crate) fn push(&mut self, v: Value) {
+        self.0.push(v);
+    }
+
+    pub(crate) fn pop(&mut self) -> Result<Value, VmErrorKind> {
+        self.0.pop().ok_or(VmErrorKind::StackUnderflow)
+    }
+
+    fn peek(&self) -> Result<Value, VmErrorKind> {
+        self.0.last().copied().ok_or(VmErrorKind::StackUnderflow)
+    }
+
+    fn peek_mut(&mut self) -> Result<&mut Value, VmErrorKind> {
+        self.0.last_mut().ok_or(VmErrorKind::StackUnderflow)
+    }
+
+    fn get(&self, i: usize) -> Result<Value, VmErrorKind> {
+        self.0.get(i).copied().ok_or(VmErrorKind::OutOfFrame)
+    }
+
+    fn get_mut(&mut self, i: usize) -> Result<&mut Value, VmErrorKind> {
+        self.0.get_mut(i).ok_or(VmErrorKind::OutOfFrame)
+    }
+
+    // The typed versions of pop check the type of the Value
+    pub(crate) fn pop_int(&mut self) -> Result<isize, VmErrorKind> {
+        match self.pop()? {
+            Value::Int(i) => Ok(i),
+            v => Err(VmErrorKind::type_error("int", v)),
+        }
+    }
+
+    // pop_ref pops a pointer to a string or an array
+    fn pop_ref(&mut self) -> Result<isize, VmErrorKind> {
+        match self.pop()? {
+            Value::Str(p) | Value::Array(p) => Ok(p),
+            v => Err(VmErrorKind::type_error("str or array", v)),
+        }
+    }
+}
+
+// The different ways a program can fault at runtime.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VmErrorKind {
+    // Popped or peeked an empty stack
+    StackUnderflow,
+    // Get/Set/GetArg/SetArg pointed outside of the stack, or GetArg/SetArg
+    // was used outside of a procedure
+    OutOfFrame,
+    // Ret or TailCall was executed with an empty CallStack
+    ReturnWithoutFrame,
+    DivisionByZero,
+    // An arithmetic instruction overflowed an isize
+    Overflow,
+    // Alloc, Free, Load or Store was given a bad pointer, offset or size
+    Heap(HeapError),
+    // An instruction got a Value of the wrong type
+    TypeError {
+        expected: &'static str,
+        found: &'static str,
+    },
+    // A procedure with a Signature returned with the wrong number of
+    // values in its frame, or a native function returned a different
+    // number of values than it was declared to. found is negative if a
+    // procedure used values below its frame.
+    WrongResultCount {
+        expected: usize,
+        found: isize,
+    },
+    // The program went over one of the limits in its VmConfig
+    LimitExceeded(Limit),
+    // Reading the input, or writing the output or diagnostics, failed
+    Io(std::io::ErrorKind),
+    // ReadInt read something which isn't an int
+    InvalidInput,
+    // A native function was called without being registered
+    UnknownNative(String),
+    // A native function was called with fewer values on the stack than
+    // it takes arguments
+    NativeArity {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    // A native function returned an error
+    NativeFailed {
+        name: String,
+        message: String,
+    },
+    // Syscall was given a number which isn't in the table
+    UnknownSyscall(usize),
+    // A Syscall needs a Capability which the VmConfig doesn't give
+    Denied {
+        syscall: &'static str,
+        capability: Capability,
+    },
+    // A Syscall was given a file descriptor which isn't open, or which
+    // was opened the other way, like writing to a file opened to read
+    BadFile(isize),
+    // `open` was given a mode other than 0, 1 or 2
+    InvalidMode(isize),
+    // Enter asked for more locals than there's memory for
+    TooManyLocals(usize),
+    // Exit was given a status outside of 0..=MAX_EXIT_STATUS
+    InvalidStatus(isize),
+    // PrintC was given an int which isn't a byte
+    InvalidByte(isize),
+}
+
+// The limits a VmConfig can set, each along with its maximum.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    // Values on the Stack
+    Stack(usize),
+    // StackFrames on the CallStack
+    Calls(usize),
+    // Instructions executed
+    Instructions(u64),
+    // Wall-clock time spent running
+    Time(Duration),
+    // Words in live Heap blocks
+    Heap(usize),
+}
+
+impl std::fmt::Display for Limit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Limit::Stack(n) => write!(f, "the stack grew past {} values", n),
+            Limit::Calls(n) => write!(f, "the call stack grew past {} frames", n),
+            Limit::Instructions(n) => write!(f, "used up its budget of {} instructions", n),
+            Limit::Time(t) => write!(f, "ran for longer than {:?}", t),
+            Limit::Heap(n) => write!(f, "the heap grew past {} words", n),
+        }
+    }
+}
+
+// A VmConfig caps the resources a program can use, so that a program
+// which loops forever or keeps pushing values is stopped with a
+// LimitExceeded error rather than taking the host down with it. Every
+// limit is off by default.
+//
+// Example:
+//
+// let config = VmConfig {
+//     max_instructions: Some(1_000_000),
+//     timeout: Some(Duration::from_secs(1)),
+//     ..VmConfig::default()
+// };
+// Vm::with_config(program, config).run()?;
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct VmConfig {
+    pub max_stack: Option<usize>,
+    pub max_calls: Option<usize>,
+    pub max_instructions: Option<u64>,
+    pub max_heap: Option<usize>,
+    pub timeout: Option<Duration>,
+    // What Syscall is allowed to do (see syscall.rs)
+    pub capabilities: Capabilities,
+}
+
+// The biggest status a program can Exit with. Statuses above it are
+// left for `vm` to report its own errors with (see exit_code).
+pub const MAX_EXIT_STATUS: i32 = 63;
+
+// Reading the clock is slow next to running an instruction, so the
+// timeout is only checked this often.
+const TIMEOUT_INTERVAL: u64 = 1024;
+
+impl VmErrorKind {
+    // exit_code gives each kind of fault its own process exit status,
+    // which `vm` exits with, so that scripts can tell them apart.
+    //
+    // They start at 200, which keeps them clear of the statuses programs
+    // can Exit with (0..=MAX_EXIT_STATUS), of the statuses `vm` itself
+    // uses for bad command lines and files, of the 101 a Rust panic
+    // exits with, and of the 128 + n a shell reports for signal n
+    // (which only go up to 64).
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            VmErrorKind::StackUnderflow => 200,
+            VmErrorKind::OutOfFrame => 201,
+            VmErrorKind::ReturnWithoutFrame => 202,
+            VmErrorKind::DivisionByZero => 203,
+            VmErrorKind::Overflow => 204,
+            VmErrorKind::Heap(_) => 205,
+            VmErrorKind::TypeError { .. } => 206,
+            VmErrorKind::WrongResultCount { .. } => 207,
+            VmErrorKind::LimitExceeded(_) => 208,
+            VmErrorKind::Io(_) => 209,
+            VmErrorKind::InvalidInput => 210,
+            VmErrorKind::UnknownNative(_) => 211,
+            VmErrorKind::NativeArity { .. } => 212,
+            VmErrorKind::NativeFailed { .. } => 213,
+            VmErrorKind::UnknownSyscall(_) => 214,
+            VmErrorKind::Denied { .. } => 215,
+            VmErrorKind::BadFile(_) => 216,
+            VmErrorKind::InvalidMode(_) => 217,
+            VmErrorKind::TooManyLocals(_) => 218,
+            VmErrorKind::InvalidStatus(_) => 219,
+            VmErrorKind::InvalidByte(_) => 220,
+        }
+    }
+
+    pub(crate) fn type_error(expected: &'static str, found: Value) -> Self {
+        VmErrorKind::TypeError {
+            expected,
+            found: found.type_name(),
+        }
+    }
+}
+
+impl std::fmt::Display for VmErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let msg = match self {
+            VmErrorKind::StackUnderflow => "stack underflow",
+            VmErrorKind::OutOfFrame => "access outside of the stack frame",
+            VmErrorKind::ReturnWithoutFrame => "returned with no stack frame",
+            VmErrorKind::DivisionByZero => "division by zero",
+            VmErrorKind::Overflow => "arithmetic overflow",
+            VmErrorKind::Heap(e) => return write!(f, "heap error: {}", e),
+            VmErrorKind::TypeError { expected, found } => {
+                return write!(f, "type error: expected {}, found {}", expected, found)
+            }
+            VmErrorKind::WrongResultCount { expected, found } => {
+                return write!(
+                    f,
+                    "returned {} value{} but the signature says {}",
+                    found,
+                    if *found == 1 { "" } else { "s" },
+                    expected
+                )
+            }
+            VmErrorKind::LimitExceeded(limit) => return write!(f, "limit exceeded: {}", limit),
+            VmErrorKind::Io(kind) => {
+                return write!(f, "I/O error: {}", std::io::Error::from(*kind))
+            }
+            VmErrorKind::InvalidInput => "the input isn't an int",
+            VmErrorKind::UnknownNative(name) => {
+                return write!(f, "`{}` isn't a registered native function", name)
+            }
+            VmErrorKind::NativeArity {
+                name,
+                expected,
+                found,
+            } => {
+                return write!(
+                    f,
+                    "`{}` takes {} argument{} but the stack only holds {}",
+                    name,
+                    expected,
+                    if *expected == 1 { "" } else { "s" },
+                    found
+                )
+            }
+            VmErrorKind::NativeFailed { name, message } => {
+                return write!(f, "`{}` failed: {}", name, message)
+            }
+            VmErrorKind::UnknownSyscall(n) => return write!(f, "there's no syscall {}", n),
+            VmErrorKind::Denied {
+                syscall,
+                capability,
+            } => {
+                return write!(
+                    f,
+                    "`{}` needs the {} capability, which this VM doesn't have",
+                    syscall, capability
+                )
+            }
+            VmErrorKind::BadFile(fd) => return write!(f, "{} isn't an open file", fd),
+            VmErrorKind::InvalidMode(mode) => {
+                return write!(f, "{} isn't a mode `open` knows about", mode)
+            }
+            VmErrorKind::TooManyLocals(n) => {
+                return write!(f, "there isn't enough memory for {} locals", n)
+            }
+            VmErrorKind::InvalidStatus(status) => {
+                return write!(
+                    f,
+                    "{} isn't an exit status between 0 and {}",
+                    status, MAX_EXIT_STATUS
+                )
+            }
+            VmErrorKind::InvalidByte(c) => {
+                return write!(f, "{} isn't a byte between 0 and 255", c)
+            }
+        };
+        write!(f, "{}", msg)
+    }
+}
+
+// A VmError is a VmErrorKind along with the instruction pointer
+// and the instruction that caused it.
+#[derive(Debug)]
+pub struct VmError {
+    pub kind: VmErrorKind,
+    pub ip: Pointer,
+    pub instruction: Instruction,
+}
+
+impl std::fmt::Display for VmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "runtime error: {} at instruction {} ({:?})",
+            self.kind, self.ip, self.instruction
+        )
+    }
+}
+
+impl std::error::Error for VmError {}
+
+impl From<HeapError> for VmErrorKind {
+    fn from(e: HeapError) -> Self {
+        VmErrorKind::Heap(e)
+    }
+}
+
+impl From<std::io::Error> for VmErrorKind {
+    fn from(e: std::io::Error) -> Self {
+        VmErrorKind::Io(e.kind())
+    }
+}
+
+// A Native is a Rust function which bytecode can call like a
+// procedure. It takes as many arguments as the program declares it
+// with, passed the way GetArg sees them, so args[0] is the value on top
+// of the stack. It returns the values to replace its arguments with,
+// like a procedure with a Signature, or an error message which stops
+// the program.
+//
+// Example, for `.native sqrt 1 -> 1`:
+//
+// vm.register_native("sqrt", |args| match args[0] {
+//     Value::Int(n) => Ok(vec![Value::Int((n as f64).sqrt() as isize)]),
+//     v => Err(format!("can't take the square root of a {}", v.type_name())),
+// });
+pub type Native = Box<dyn FnMut(&[Value]) -> Result<Vec<Value>, String> + Send>;
+
+// What happened during a call to Vm::run_for
+#[derive(Debug)]
+pub enum RunStatus {
+    // The budget ran out before the program ended. Calling run_for
+    // again carries on from where it stopped.
+    Yielded,
+    // The program ran off the end
+    Halted,
+    // An instruction faulted. The Vm is left as it was when it faulted,
+    // so it can be inspected, but it shouldn't be resumed.
+    Error(VmError),
+}
+
+// The Vm owns a Program along with all of the state needed to run it:
+// the Stack, the CallStack, the Heap, and the instruction pointer. It
+// also owns the input the program reads and the sinks it prints to
+// (see io.rs).
+//
+// snapshot.rs saves and restores the state directly, and syscall.rs
+// works on it directly too.
+pub struct Vm {
+    program: Program,
+    pub(crate) stack: Stack,
+    pub(crate) call_stack: CallStack,
+    pub(crate) heap: Heap,
+    pub(crate) pointer: Pointer,
+    pub(crate) input: Input,
+    pub(crate) output: Sink,
+    pub(crate) diagnostics: Sink,
+    pub(crate) config: VmConfig,
+    // How many instructions have been executed
+    executed: u64,
+    // When the first instruction was executed
+    started: Option<Instant>,
+    // The value of executed at which the instruction and time limits
+    // next need checking
+    checkpoint: u64,
+    // natives[i] is the function registered for program.natives[i],
+    // if there is one
+    natives: Vec<Option<Native>>,
+    // The program's command line arguments
+    pub(crate) args: Vec<String>,
+    // The files opened by Syscall, by their descriptor minus 3
+    pub(crate) files: Vec<Option<File>>,
+    // When the Vm was created, which the clock Syscall counts from
+    pub(crate) created: Instant,
+    // The status the program exited with, if it exited
+    exit_status: Option<i32>,
+}
+
+impl Vm {
+    pub fn new(program: Program) -> Self {
+        Vm::with_config(program, VmConfig::default())
+    }
+
+    pub fn with_config(program: Program, config: VmConfig) -> Self {
+        // The data blocks go first, so that they end up where
+        // Program::data_pointers says they will
+        let mut heap = Heap::new();
+        for block in program.data.iter() {
+            heap.alloc_values(block);
+        }
+
+        Vm {
+            stack: Stack::default(),
+            call_stack: CallStack::new(),
+            heap,
+            pointer: 0,
+            input: Input::new(io::stdin()),
+            output: io::stdout(),
+            diagnostics: io::stderr(),
+            config,
+            executed: 0,
+            started: None,
+            checkpoint: 0,
+            natives: program.natives.iter().map(|_| None).collect(),
+            args: Vec::new(),
+            files: Vec::new(),
+            created: Instant::now(),
+            exit_status: None,
+            program,
+        }
+    }
+
+    // register_native makes `Call name` run f, for programs which
+    // declare a native called name. It replaces any native registered
+    // under the same name before. If the program doesn't declare it,
+    // nothing is registered and it returns false, since the program
+    // can't call it anyway.
+    pub fn register_native<F>(&mut self, name: &str, f: F) -> bool
+    where
+        F: FnMut(&[Value]) -> Result<Vec<Value>, String> + Send + 'static,
+    {
+        match self.program.natives.iter().position(|(n, _)| n == name) {
+            Some(i) => {
+                self.natives[i] = Some(Box::new(f));
+                true
+            }
+            None => false,
+        }
+    }
+
+    // unregistered_natives returns the natives the program declares
+    // which haven't been registered, so that a host can refuse to run
+    // it rather than have it fail halfway through.
+    pub fn unregistered_natives(&self) -> Vec<&str> {
+        self.program
+            .natives
+            .iter()
+            .zip(self.natives.iter())
+            .filter(|(_, f)| f.is_none())
+            .map(|((name, _), _)| name.as_str())
+            .collect()
+    }
+
+    // set_input makes ReadInt and ReadC read from input, rather than
+    // stdin.
+    pub fn set_input(&mut self, input: Source) {
+        self.input = Input::new(input);
+    }
+
+    // set_output sends what Print, PrintC and PrintS print to out,
+    // rather than stdout.
+    pub fn set_output(&mut self, out: Sink) {
+        self.output = out;
+    }
+
+    // set_diagnostics sends what PrintStack prints to out, rather
+    // than stderr.
+    pub fn set_diagnostics(&mut self, out: Sink) {
+        self.diagnostics = out;
+    }
+
+    // set_args sets the command line arguments which the arg Syscall
+    // reads. By convention the first one is the name of the program.
+    pub fn set_args(&mut self, args: Vec<String>) {
+        self.args = args;
+    }
+
+    // flush flushes the output, the diagnostics, and any files the
+    // program has open. run and run_for do this before they return, but
+    // they ignore any errors, so a host which cares whether everything
+    // was written should flush again itself.
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        for file in self.files.iter_mut() {
+            if let Some(File::Write(f)) = file {
+                f.flush()?;
+            }
+        }
+        self.output.flush()?;
+        self.diagnostics.flush()
+    }
+
+    // exit_status returns the status the program stopped with, or None
+    // if it hasn't stopped with Halt, Exit or the exit Syscall. A
+    // program which runs off the end doesn't have one.
+    pub fn exit_status(&self) -> Option<i32> {
+        self.exit_status
+    }
+
+    // exit stops the program with a status, for Halt, Exit and the exit
+    // Syscall. It moves the instruction pointer off the end, so that the
+    // program finishes the same way as one which runs out of instructions.
+    pub(crate) fn exit(&mut self, status: isize) -> Result<(), VmErrorKind> {
+        match i32::try_from(status) {
+            Ok(status) if (0..=MAX_EXIT_STATUS).contains(&status) => {
+                self.exit_status = Some(status);
+                self.pointer = self.program.instructions.len();
+                Ok(())
+            }
+            _ => Err(VmErrorKind::InvalidStatus(status)),
+        }
+    }
+
+    pub fn config(&self) -> &VmConfig {
+        &self.config
+    }
+
+    pub fn executed(&self) -> u64 {
+        self.executed
+    }
+
+    pub fn program(&self) -> &Program {
+        &self.program
+    }
+
+    pub fn stack(&self) -> &[Value] {
+        &self.stack.0
+    }
+
+    pub fn call_stack(&self) -> &[StackFrame] {
+        &self.call_stack
+    }
+
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    pub fn pointer(&self) -> Pointer {
+        self.pointer
+    }
+
+    // The offset of the current StackFrame, or 0 outside of a procedure
+    pub fn frame_offset(&self) -> Pointer {
+        self.call_stack.last().map_or(0, |s| s.stack_offset)
+    }
+
+    // is_finished checks whether the instruction pointer has run off the
+    // end of the program.
+    pub fn is_finished(&self) -> bool {
+        self.pointer >= self.program.instructions.len()
+    }
+
+    // stack_string formats the stack like the comments in the test files,
+    // with a pipe at the current StackFrame's offset.
+    //
+    // Example: [1, 3, 2 | 5, 7]
+    pub fn stack_string(&self) -> String {
+        let join = |values: &[Value]| {
+            values
+                .iter()
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+
+        if self.call_stack.is_empty() {
+            return format!("[{}]", join(&self.stack.0));
+        }
+
+        let (args, locals) = self
+            .stack
+            .0
+            .split_at(self.frame_offset().min(self.stack.0.len()));
+        match (args.is_empty(), locals.is_empty()) {
+            (true, true) => "[|]".to_string(),
+            (false, true) => format!("[{} |]", join(args)),
+            (true, false) => format!("[| {}]", join(locals)),
+            (false, false) => format!("[{} | {}]", join(args), join(locals)),
+        }
+    }
+
+    // run executes instructions until the instruction pointer runs off
+    // the end of the program, or until an instruction faults.
+    pub fn run(&mut self) -> Result<(), VmError> {
+        let res = loop {
+            match self.step() {
+                Ok(true) => {}
+                Ok(false) => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        self.flush().ok();
+        res
+    }
+
+    // run_for executes at most budget instructions, then hands control
+    // back. Everything the program needs is kept in the Vm, so a host
+    // can run many Vms on one thread by giving each a slice at a time:
+    //
+    // while !vms.is_empty() {
+    //     vms.retain_mut(|vm| matches!(vm.run_for(1000), RunStatus::Yielded));
+    // }
+    pub fn run_for(&mut self, budget: u64) -> RunStatus {
+        let mut status = RunStatus::Yielded;
+        for _ in 0..budget {
+            match self.step() {
+                Ok(true) => {}
+                Ok(false) => {
+                    status = RunStatus::Halted;
+                    break;
+                }
+                Err(e) => {
+                    status = RunStatus::Error(e);
+                    break;
+                }
+            }
+        }
+        self.flush().ok();
+
+        match status {
+            RunStatus::Yielded if self.is_finished() => RunStatus::Halted,
+            status => status,
+        }
+    }
+
+    // step executes a single instruction. It returns false if there
+    // was no instruction left to execute.
+    #[inline]
+    pub fn step(&mut self) -> Result<bool, VmError> {
+        let instruction = match self.program.instructions.get(self.pointer) {
+            Some(&instruction) => instruction,
+            None => return Ok(false),
+        };
+
+        let ip = self.pointer;
+        let fault = |kind| VmError {
+            kind,
+            ip,
+            instruction,
+        };
+
+        if self.executed >= self.checkpoint {
+            self.check_budget().map_err(fault)?;
+        }
+
+        self.pointer += 1;
+        self.executed += 1;
+        self.execute(instruction).map_err(fault)?;
+
+        match self.config.max_stack {
+            Some(max) if self.stack.0.len() > max => {
+                Err(fault(VmErrorKind::LimitExceeded(Limit::Stack(max))))
+            }
+            _ => Ok(true),
+        }
+    }
+
+    // check_budget checks the instruction and time limits before an
+    // instruction runs, so that it doesn't run if they've been used up,
+    // then works out when they next need checking.
+    #[cold]
+    fn check_budget(&mut self) -> Result<(), VmErrorKind> {
+        let mut checkpoint = u64::MAX;
+
+        if let Some(max) = self.config.max_instructions {
+            if self.executed >= max {
+                return Err(VmErrorKind::LimitExceeded(Limit::Instructions(max)));
+            }
+            checkpoint = max;
+        }
+
+        if let Some(timeout) = self.config.timeout {
+            let started = *self.started.get_or_insert_with(Instant::now);
+            if started.elapsed() > timeout {
+                return Err(VmErrorKind::LimitExceeded(Limit::Time(timeout)));
+            }
+            checkpoint = checkpoint.min(self.executed + TIMEOUT_INTERVAL);
+        }
+
+        self.checkpoint = checkpoint;
+        Ok(())
+    }
+
+    // `execute` runs a single instruction. Any fault is returned as a
+    // VmErrorKind, which `run()` turns into a VmError by attaching
+    // the faulting instruction pointer and instruction.
+    fn execute(&mut self, instruction: Instruction) -> Result<(), VmErrorKind> {
+        use Instruction::*;
+
+        let Vm {
+            stack,
+            pointer,
+            call_stack,
+            heap,
+            input,
+            output,
+            diagnostics,
+            config,
+            program,
+            natives,
+            ..
+        } = self;
+
+        match instruction {
+            // Noop doesn't do anything. However, it's used as a placeholder
+            // for labels and procedures in the code.
+            Noop => {}
+
+            // Push pushes a value to the top of the stack.
+            Push(d) => stack.push(d),
+
+            // Pop removes a value from the top of the stack.
+            Pop => {
+                stack.pop()?;
+            }
+
+            // Add pops the two top values, adds them, and pushes
+            // the result.
+            //
+            // Before:
+            // [.., a, b]
+            //
+            // After:
+            // [.., a + b]
+            //
+            // Both values have to be ints, or both have to be floats.
+            Add => {
+                let (a, b) = (stack.pop()?, stack.pop()?);
+                stack.push(binary_op(Add, a, b)?)
+            }
+
+            // Sub pops the two top values, and pushes the difference.
+            // Importantly, the order of operations is switched.
+            //
+            // This is a bit more intuitive because the stack is
+            // usually reasoned about from left to right.
+            //
+            // Before:
+            // [.., a, b]
+            //
+            // After:
+            // [.., b - a]
+            Sub => {
+                let (a, b) = (stack.pop()?, stack.pop()?);
+                stack.push(binary_op(Sub, a, b)?)
+            }
+
+            // I think you can figure out Mul and Div
+            Mul => {
+                let (a, b) = (stack.pop()?, stack.pop()?);
+                stack.push(binary_op(Mul, a, b)?)
+            }
+            Div => {
+                let (a, b) = (stack.pop()?, stack.pop()?);
+                stack.push(binary_op(Div, a, b)?)
+            }
+
+            // Incr and Decr increment or decrement the value
+            // at the top of the stack.
+            //
+            // These instructions are redundant because of Add and Sub,
+            // but they improve performance significantly because they
+            // remove an unecessary Push.
+            Incr => {
+                let top = stack.peek_mut()?;
+                *top = binary_op(Add, one_like(*top), *top)?;
+            }
+            Decr => {
+                let top = stack.peek_mut()?;
+                *top = binary_op(Sub, one_like(*top), *top)?;
+            }
+
+            // Jump unconditionally changes the stack pointer
+            Jump(p) => *pointer = p,
+
+            // JE changes the stack pointer if the value
+            // on top of the stack is zero. This is generally
+            // used after Sub for equality testing, hence the
+            // name of the instruction, Jump (if) Equal.
+            //
+            // Example:
+            //
+            // PrintStack -- [.., a, b]
+            // Sub
+            // PrintStack -- [.., b - a] // this will be zero if a and b are equal
+            // JE i // jumps to Instruction i if a and b were equal.
+            //
+            // Ints and floats are compared to zero, and bools count as
+            // 1 for true and 0 for false.
+            JE(p) => {
+                if compare_zero(stack.peek()?)? == Some(Ordering::Equal) {
+                    stack.pop()?;
+                    *pointer = p;
+                }
+            }
+
+            // JNE (Jump Not Equal) changes the stack pointer
+            // if the value on top of the stack is *not* zero.
+            JNE(p) => {
+                if compare_zero(stack.peek()?)? != Some(Ordering::Equal) {
+                    stack.pop()?;
+                    *pointer = p;
+                }
+            }
+
+            // JGT (Jump Greater Than) changes the stack pointer
+            // if the value on top of the stack is greater than zero.
+            JGT(p) => {
+                if compare_zero(stack.peek()?)? == Some(Ordering::Greater) {
+                    stack.pop()?;
+                    *pointer = p;
+                }
+            }
+
+            // JLT (Jump Less Than) changes the stack pointer
+            // if the value on top of the stack is less than zero.
+            JLT(p) => {
+                if compare_zero(stack.peek()?)? == Some(Ordering::Less) {
+                    stack.pop()?;
+                    *pointer = p;
+                }
+            }
+
+            // JGE (Jump Greater Equal) changes the stack pointer
+            // if the value on top of the stack is greater than
+            // or equal to zero.
+            JGE(p) => {
+                if matches!(
+                    compare_zero(stack.peek()?)?,
+                    Some(Ordering::Greater) | Some(Ordering::Equal)
+                ) {
+                    stack.pop()?;
+                    *pointer = p;
+                }
+            }
+
+            // JLE (Jump Less Equal) changes the stack pointer
+            // if the value on top of the stack is greater than
+            // or equal to zero.
+            JLE(p) => {
+                if matches!(
+                    compare_zero(stack.peek()?)?,
+                    Some(Ordering::Less) | Some(Ordering::Equal)
+                ) {
+                    stack.pop()?;
+                    *pointer = p;
+                }
+            }
+
+            // The above instructions can be confusing because they
+            // don't quite match the naming semantics of JE and JNE.
+            // For example, when used after a Sub, JGT will jump if
+            // a was *less* than b, not greater than it, because
+            // if a is *less* than b, b - a will be *greater* than zero.
+
+            // Get pushes the value at index i to the top of the stack.
+            //
+            // Example:
+            //
+            // PrintStack -- [0, 1, 3, 2, 5]
+            // Get 2
+            // PrintStack -- [0, 1, 3, 2, 5, 3]
+            //
+            // Remember that values are indexed relatively to the stackframe
+            // as explained near the start of this file.
+            Get(i) => {
+                let v = stack.get(local_index(call_stack, i)?)?;
+                stack.push(v)
+            }
+
+            // Set sets the value at index i to be equal to the value
+            // at the top of the stack. It does *not* pop the top value.
+            //
+            // Example:
+            //
+            // PrintStack -- [0, 1, 3, 2, 5]
+            // Set 2
+            // PrintStack -- [0, 1, 5, 2, 5]
+            //
+            // Remember that values are indexed relatively to the stackframe
+            // as explained near the start of this file.
+            Set(i) => {
+                let new_val = stack.peek()?;
+                *stack.get_mut(local_index(call_stack, i)?)? = new_val;
+            }
+
+            // SetPop is a Set followed by a Pop. The optimizer fuses
+            // the two, since storing a local and dropping it is how
+            // most loops update their counters.
+            SetPop(i) => {
+                let new_val = stack.peek()?;
+                *stack.get_mut(local_index(call_stack, i)?)? = new_val;
+                stack.pop()?;
+            }
+
+            // GetArg and SetArg mirror Get and Set.
+            GetArg(i) => {
+                let v = stack.get(arg_index(call_stack, i)?)?;
+                stack.push(v)
+            }
+            SetArg(i) => {
+                let new_val = stack.peek()?;
+                *stack.get_mut(arg_index(call_stack, i)?)? = new_val;
+            }
+
+            // Print prints the value at the top of the stack.
+            // Strings and arrays are printed out of the Heap.
+            Print => write!(output, "{}", format_value(heap, stack.peek()?, 0)?)?,
+
+            // PrintC writes the int at the top of the stack as a
+            // single byte, which is its ASCII character if it's under
+            // 128. Since it's a byte rather than a char, printing what
+            // ReadC reads copies UTF-8 through intact. Ints which don't
+            // fit in a byte are an error rather than being cut down.
+            PrintC => match stack.peek()? {
+                Value::Int(c) => {
+                    let byte = u8::try_from(c).map_err(|_| VmErrorKind::InvalidByte(c))?;
+                    output.write_all(&[byte])?
+                }
+                v => return Err(VmErrorKind::type_error("int", v)),
+            },
+
+            // PrintS prints the string which the top of the stack
+            // points to. Unlike Print, it faults on anything else.
+            PrintS => match stack.peek()? {
+                v @ Value::Str(_) => write!(output, "{}", format_value(heap, v, 0)?)?,
+                v => return Err(VmErrorKind::type_error("str", v)),
+            },
+
+            // ReadInt reads the next whitespace separated word from the
+            // input and parses it as an int. It pushes the int and then
+            // true, or 0 and then false at the end of the input, so the
+            // end can be checked for with JE.
+            //
+            // If it has to wait for more input, it flushes the output
+            // first, so that a prompt shows up before it's answered.
+            //
+            // Before:
+            // [..]
+            //
+            // After:
+            // [.., n, true]
+            ReadInt => {
+                let word = input.read_word(&mut || flush(output, diagnostics))?;
+                if word.is_empty() {
+                    stack.push(Value::Int(0));
+                    stack.push(Value::Bool(false));
+                } else {
+                    let n = std::str::from_utf8(&word)
+                        .ok()
+                        .and_then(|w| w.parse().ok())
+                        .ok_or(VmErrorKind::InvalidInput)?;
+                    stack.push(Value::Int(n));
+                    stack.push(Value::Bool(true));
+                }
+            }
+
+            // ReadC reads a single byte from the input and pushes it,
+            // or pushes -1 at the end of the input.
+            ReadC => {
+                let c = input.read_byte(&mut || flush(output, diagnostics))?;
+                stack.push(Value::Int(c.map_or(-1, isize::from)))
+            }
+
+            // PrintStack prints the whole stack. It's meant to be
+            // used for debugging, so it goes to the diagnostics rather
+            // than the output.
+            PrintStack => {
+                let values = stack.0.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+                writeln!(diagnostics, "[{}]", values.join(", "))?
+            }
+
+            // Call calls a procedure, pushing a new StackFrame.
+            // Details about the StackFrame can be found near the
+            // start of the file.
+            Call(p) => {
+                if let Some(max) = config.max_calls {
+                    if call_stack.len() >= max {
+                        return Err(VmErrorKind::LimitExceeded(Limit::Calls(max)));
+                    }
+                }
+                call_stack.push(StackFrame {
+                    stack_offset: stack.0.len(),
+                    ip: *pointer,
+                });
+                *pointer = p;
+            }
+
+            // CallNative calls a native function, which takes its
+            // arguments off the top of the stack and pushes its results
+            // in their place. It doesn't push a StackFrame, since the
+            // native runs in Rust.
+            //
+            // Call sqrt
+            //
+            // Before:
+            // [.., 16]
+            //
+            // After:
+            // [.., 4]
+            CallNative(i) => {
+                let (name, sig) = match program.natives.get(i) {
+                    Some((name, sig)) => (name, *sig),
+                    None => return Err(VmErrorKind::UnknownNative(format!("native {}", i))),
+                };
+                let f = natives
+                    .get_mut(i)
+                    .and_then(Option::as_mut)
+                    .ok_or_else(|| VmErrorKind::UnknownNative(name.clone()))?;
+
+                let base = stack.0.len().checked_sub(sig.args).ok_or_else(|| {
+                    VmErrorKind::NativeArity {
+                        name: name.clone(),
+                        expected: sig.args,
+                        found: stack.0.len(),
+                    }
+                })?;
+                let args: Vec<Value> = stack.0[base..].iter().rev().copied().collect();
+
+                let results = f(&args).map_err(|message| VmErrorKind::NativeFailed {
+                    name: name.clone(),
+                    message,
+                })?;
+                // The verifier trusts the declaration, so it has to hold
+                if results.len() != sig.results {
+                    return Err(VmErrorKind::WrongResultCount {
+                        expected: sig.results,
+                        found: results.len() as isize,
+                    });
+                }
+                stack.0.truncate(base);
+                stack.0.extend(results);
+            }
+
+            // TailCall calls a procedure in place of the current one.
+            // Rather than pushing a new StackFrame, it moves the current
+            // frame's offset up to the top of the stack, so when the
+            // procedure returns it goes straight back to whoever called
+            // this one. That's what `Call p; Ret` does too, but without
+            // the call stack growing, so recursive procedures which end
+            // in a call can recurse as deep as they like.
+            TailCall(p) => {
+                let frame = call_stack
+                    .last_mut()
+                    .ok_or(VmErrorKind::ReturnWithoutFrame)?;
+                frame.stack_offset = stack.0.len();
+                *pointer = p;
+            }
+
+            // Ret returns from the current procedure, popping the
+            // stack frame from the top of the call stack and returning
+            // to the instruction list at the index right after it was called at.
+            Ret => *pointer = call_stack.pop().ok_or(VmErrorKind::ReturnWithoutFrame)?.ip,
+
+            // Return is what Ret compiles to in a procedure with a
+            // Signature. It checks that the frame holds exactly as many
+            // values as the procedure returns, then drops the arguments
+            // from under them, so the caller doesn't have to.
+            //
+            // Proc add 2 -> 1
+            //
+            // Before:
+            // [.., a, b | a + b]
+            //
+            // After:
+            // [.., a + b]
+            Return(args, results) => {
+                let frame = *call_stack.last().ok_or(VmErrorKind::ReturnWithoutFrame)?;
+                let found = stack.0.len() as isize - frame.stack_offset as isize;
+                if found != results as isize {
+                    return Err(VmErrorKind::WrongResultCount {
+                        expected: results,
+                        found,
+                    });
+                }
+
+                let base = frame
+                    .stack_offset
+                    .checked_sub(args)
+                    .ok_or(VmErrorKind::OutOfFrame)?;
+                stack.0.drain(base..frame.stack_offset);
+                call_stack.pop();
+                *pointer = frame.ip;
+            }
+
+            // Enter reserves n locals at the start of a procedure by
+            // pushing n zeroes, which Get and Set can then use.
+            //
+            // Enter 2
+            //
+            // Before:
+            // [.., a | ]
+            //
+            // After:
+            // [.., a | 0, 0]
+            //
+            // Enter is checked against the stack limit before it pushes
+            // anything, since n can be big enough to run out of memory.
+            // Without a limit, it still fails rather than aborting if
+            // there's no room for n more values.
+            Enter(n) => {
+                if let Some(max) = config.max_stack {
+                    if n > max.saturating_sub(stack.0.len()) {
+                        return Err(VmErrorKind::LimitExceeded(Limit::Stack(max)));
+                    }
+                }
+                let len = stack
+                    .0
+                    .len()
+                    .checked_add(n)
+                    .ok_or(VmErrorKind::TooManyLocals(n))?;
+                stack
+                    .0
+                    .try_reserve_exact(n)
+                    .map_err(|_| VmErrorKind::TooManyLocals(n))?;
+                stack.0.resize(len, Value::default())
+            }
+
+            // RetN is what `Ret n` compiles to. It keeps the top n values
+            // of the frame, throws away everything else the procedure
+            // pushed, and returns. In a procedure with a Signature it
+            // drops the arguments too, like Return.
+            //
+            // Ret 1
+            //
+            // Before:
+            // [.., a | x, y, z]
+            //
+            // After:
+            // [.., a, z]
+            RetN(args, keep) => {
+                let frame = *call_stack.last().ok_or(VmErrorKind::ReturnWithoutFrame)?;
+                let top = match stack.0.len().checked_sub(keep) {
+                    Some(top) if top >= frame.stack_offset => top,
+                    _ => return Err(VmErrorKind::OutOfFrame),
+                };
+
+                let base = frame
+                    .stack_offset
+                    .checked_sub(args)
+                    .ok_or(VmErrorKind::OutOfFrame)?;
+                stack.0.drain(base..top);
+                call_stack.pop();
+                *pointer = frame.ip;
+            }
+
+            // Halt stops the program with the status on top of the stack,
+            // like Exit, but leaves the status where it is, so that the
+            // stack can still be looked at once the program has stopped.
+            //
+            // Before:
+            // [.., status]
+            //
+            // After:
+            // [.., status]
+            Halt => match stack.peek()? {
+                Value::Int(status) => self.exit(status)?,
+                v => return Err(VmErrorKind::type_error("int", v)),
+            },
+
+            // Exit pops a status and stops the program with it, which
+            // `vm` exits with in turn. Anything other than 0 means the
+            // program failed, and the status has to be between 0 and
+            // MAX_EXIT_STATUS.
+            //
+            // Before:
+            // [.., status]
+            Exit => {
+                let status = stack.pop_int()?;
+                self.exit(status)?
+            }
+
+            // Syscall n calls into the host, for files, the clock and
+            // the like. The table of what each n does is in syscall.rs.
+            Syscall(n) => syscall::call(self, n)?,
+
+            // Alloc pops a size and allocates a zeroed block of that many
+            // words on the Heap, pushing a pointer to it.
+            //
+            // Before:
+            // [.., n]
+            //
+            // After:
+            // [.., pointer]
+            //
+            // Like Enter, Alloc is checked against the heap limit before
+            // it allocates anything.
+            Alloc => {
+                let n = stack.pop_int()?;
+                check_heap(config, heap, n.max(0) as usize)?;
+                stack.push(Value::Array(heap.alloc(n)?))
+            }
+
+            // Free pops a pointer and frees the block it points to.
+            Free => {
+                let p = stack.pop_ref()?;
+                heap.free(p)?
+            }
+
+            // Load pops an offset and a pointer, and pushes the word
+            // at that offset into the block.
+            //
+            // Before:
+            // [.., pointer, offset]
+            //
+            // After:
+            // [.., value]
+            Load => {
+                let (offset, p) = (stack.pop_int()?, stack.pop_ref()?);
+                stack.push(heap.load(p, offset)?)
+            }
+
+            // Store pops an offset, a pointer, and a value, and stores
+            // the value at that offset into the block.
+            //
+            // Before:
+            // [.., value, pointer, offset]
+            //
+            // After:
+            // [..]
+            Store => {
+                let (offset, p, v) = (stack.pop_int()?, stack.pop_ref()?, stack.pop()?);
+                heap.store(p, offset, v)?
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// flush flushes the output and the diagnostics, before reading input
+// which might have to wait for the user (see io::Input).
+pub(crate) fn flush(output: &mut Sink, diagnostics: &mut Sink) -> std::io::Result<()> {
+    output.flush()?;
+    diagnostics.flush()
+}
+
+// check_heap checks that allocating n more words wouldn't go over the
+// heap limit.
+pub(crate) fn check_heap(config: &VmConfig, heap: &Heap, n: usize) -> Result<(), VmErrorKind> {
+    match config.max_heap {
+        Some(max) if n > max.saturating_sub(heap.allocated()) => {
+            Err(VmErrorKind::LimitExceeded(Limit::Heap(max)))
+        }
+        _ => Ok(()),
+    }
+}
+
+// binary_op applies Add, Sub, Mul or Div to the two values popped
+// off the stack. The optimizer uses it too, to fold constants.
+pub(crate) fn binary_op(op: Instruction, a: Value, b: Value) -> Result<Value, VmErrorKind> {
+    match op {
+        Instruction::Add => arithmetic(a, b, isize::checked_add, |b, a| b + a),
+        Instruction::Sub => arithmetic(a, b, isize::checked_sub, |b, a| b - a),
+        Instruction::Mul => arithmetic(a, b, isize::checked_mul, |b, a| b * a),
+        Instruction::Div => {
+            if let (Value::Int(_), Value::Int(0)) = (b, a) {
+                return Err(VmErrorKind::DivisionByZero);
+            }
+            // isize::MIN / -1 is the only other way an int division can
+            // fail. Float division follows IEEE 754, so 1.0 / 0.0 is inf.
+            arithmetic(a, b, isize::checked_div, |b, a| b / a)
+        }
+        _ => unreachable!("{} isn't a binary operator", op.name()),
+    }
+}
+
+// arithmetic applies an operator to two numbers of the same type.
+// `a` is the value that was on top of the stack, so the operators
+// take their arguments as (b, a), like Sub's b - a.
+fn arithmetic(
+    a: Value,
+    b: Value,
+    int_op: fn(isize, isize) -> Option<isize>,
+    float_op: fn(f64, f64) -> f64,
+) -> Result<Value, VmErrorKind> {
+    match (b, a) {
+        (Value::Int(b), Value::Int(a)) => int_op(b, a).map(Value::Int).ok_or(VmErrorKind::Overflow),
+        (Value::Float(b), Value::Float(a)) => Ok(Value::Float(float_op(b, a))),
+        (Value::Int(_), v) => Err(VmErrorKind::type_error("int", v)),
+        (Value::Float(_), v) => Err(VmErrorKind::type_error("float", v)),
+        (v, _) => Err(VmErrorKind::type_error("int or float", v)),
+    }
+}
+
+// one_like returns a one of the same type as v, for Incr and Decr
+fn one_like(v: Value) -> Value {
+    match v {
+        Value::Float(_) => Value::Float(1.0),
+        _ => Value::Int(1),
+    }
+}
+
+// compare_zero compares a value to zero for the conditional jumps.
+// NaN isn't comparable to anything, so it gives None.
+fn compare_zero(v: Value) -> Result<Option<Ordering>, VmErrorKind> {
+    match v {
+        Value::Int(i) => Ok(Some(i.cmp(&0))),
+        Value::Float(x) => Ok(x.partial_cmp(&0.0)),
+        Value::Bool(b) => Ok(Some((b as isize).cmp(&0))),
+        v => Err(VmErrorKind::type_error("int, float or bool", v)),
+    }
+}
+
+// Arrays can contain themselves, so printing stops this many levels in
+const MAX_PRINT_DEPTH: usize = 8;
+
+// format_value formats a value for Print. Unlike Value's Display, it
+// looks into the Heap to print the contents of strings and arrays.
+//
+// Example: `3`, `3.14`, `true`, `Hello World`, `[1, "two", 3.0]`
+fn format_value(heap: &Heap, v: Value, depth: usize) -> Result<String, VmErrorKind> {
+    match v {
+        Value::Str(p) => heap
+            .block_values(p)?
+            .iter()
+            .map(|&c| match c {
+                Value::Int(c) => Ok(std::char::from_u32(c as u32).unwrap_or('\u{fffd}')),
+                v => Err(VmErrorKind::type_error("int", v)),
+            })
+            .collect(),
+        Value::Array(_) if depth >= MAX_PRINT_DEPTH => Ok("[...]".to_string()),
+        Value::Array(p) => {
+            let elements = heap
+                .block_values(p)?
+                .iter()
+                .map(|&e| match e {
+                    Value::Str(_) => Ok(format!("{:?}", format_value(heap, e, depth + 1)?)),
+                    _ => format_value(heap, e, depth + 1),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(format!("[{}]", elements.join(", ")))
+        }
+        v => Ok(v.to_string()),
+    }
+}
+
+// local_index turns the index of a Get/Set into an absolute
+// index into the stack.
+fn local_index(call_stack: &CallStack, i: Pointer) -> Result<usize, VmErrorKind> {
+    let offset = call_stack.last().map_or(0, |s| s.stack_offset);
+    offset.checked_add(i).ok_or(VmErrorKind::OutOfFrame)
+}
+
+// arg_index turns the index of a GetArg/SetArg into an absolute
+// index into the stack. Arguments only exist inside of a procedure.
+fn arg_index(call_stack: &CallStack, i: Pointer) -> Result<usize, VmErrorKind> {
+    let offset = call_stack
+        .last()
+        .ok_or(VmErrorKind::OutOfFrame)?
+        .stack_offset;
+    offset
+        .checked_sub(1)
+        .and_then(|o| o.checked_sub(i))
+        .ok_or(VmErrorKind::OutOfFrame)
+}
@@ -0,0 +1,95 @@
+use std::process::Command;
+
+use tinyvm::{AsmError, Assembler, Instruction, Span, Value};
+
+mod common;
+use common::{assemble, temp_path};
+
+fn errors(source: &str) -> Vec<AsmError> {
+    Assembler::new("test.bytecode")
+        .assemble(source)
+        .expect_err("test program shouldn't assemble")
+}
+
+#[test]
+fn floats_have_to_be_finite() {
+    for literal in &["inf", "-inf", "NaN", "-NaN", "infinity", "1e999", "-1e999"] {
+        let errors = errors(&format!("Push {}", literal));
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, format!("invalid value `{}`", literal));
+    }
+
+    let program = assemble("Push -1.5e3");
+    assert_eq!(
+        program.instructions,
+        [Instruction::Push(Value::Float(-1500.0))]
+    );
+}
+
+#[test]
+fn every_error_is_reported_in_order() {
+    let source = "Push 1\nJump nowhere\nFoo\n  Push \"abc\nProc p\n";
+    let errors: Vec<(Span, String)> = errors(source)
+        .into_iter()
+        .map(|e| (e.span, e.message))
+        .collect();
+
+    let span = |line, column, len| Span { line, column, len };
+    assert_eq!(
+        errors,
+        [
+            (span(2, 6, 7), "unknown label `nowhere`".to_string()),
+            (span(3, 1, 3), "unknown instruction `Foo`".to_string()),
+            (span(4, 8, 4), "unterminated string `\"abc`".to_string()),
+            (
+                span(5, 6, 1),
+                "procedure `p` is missing an `End`".to_string()
+            ),
+        ]
+    );
+}
+
+#[test]
+fn errors_show_where_they_are() {
+    let errors = errors("Push 1\n\tCall nowhere");
+    assert_eq!(
+        errors[0].to_string(),
+        "error: unknown procedure `nowhere`
+ --> test.bytecode:2:7
+  |
+2 | \tCall nowhere
+  | \t     ^^^^^^^"
+    );
+}
+
+// Assembles a file with the vm binary, returning what it printed to stderr
+fn vm_stderr(name: &str, source: &str) -> String {
+    let path = temp_path(name);
+    std::fs::write(&path, source).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_vm"))
+        .args(["assemble", &path, "-o", &temp_path("out.tvmc")])
+        .output()
+        .expect("could not run the vm binary");
+    std::fs::remove_file(&path).unwrap();
+    String::from_utf8(output.stderr).unwrap()
+}
+
+#[test]
+fn the_cli_counts_the_errors() {
+    let stderr = vm_stderr("one.bytecode", "Pushh 1");
+    assert!(stderr.ends_with("due to 1 previous error\n"), "{}", stderr);
+
+    let stderr = vm_stderr("three.bytecode", "Pushh 1\nJump x\nCall y");
+    assert_eq!(stderr.matches("error: ").count(), 4);
+    assert!(stderr.ends_with("due to 3 previous errors\n"), "{}", stderr);
+}
+
+#[test]
+fn signature_counts_have_to_fit_in_an_isize() {
+    let errors = errors("Proc f 0 -> 9223372036854775808\n    Ret\nEnd");
+    assert_eq!(errors.len(), 1);
+    assert_eq!(
+        errors[0].message,
+        "count `9223372036854775808` is too large"
+    );
+}
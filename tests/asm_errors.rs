@@ -0,0 +1,44 @@
+use tinyvm::Assembler;
+
+// Assembles a single bad line, returning the messages it was rejected with
+fn messages(source: &str) -> Vec<String> {
+    Assembler::new("test.bytecode")
+        .assemble(source)
+        .expect_err("test program shouldn't assemble")
+        .into_iter()
+        .map(|e| e.message)
+        .collect()
+}
+
+#[test]
+fn argument_counts_are_spelled_out() {
+    assert_eq!(
+        messages("Push 1 2"),
+        ["`Push` takes 1 argument but 2 were given"]
+    );
+    assert_eq!(
+        messages("Add 1"),
+        ["`Add` takes 0 arguments but 1 was given"]
+    );
+    assert_eq!(
+        messages("Jump"),
+        ["`Jump` takes 1 argument but 0 were given"]
+    );
+}
+
+#[test]
+fn optional_arguments_are_counted_too() {
+    assert_eq!(
+        messages("Proc f\n    Ret 1 2\nEnd"),
+        ["`Ret` takes 0 or 1 arguments but 2 were given"]
+    );
+    assert_eq!(
+        messages("Proc\nEnd"),
+        ["`Proc` takes 1 or 4 arguments but 0 were given"]
+    );
+}
+
+#[test]
+fn unknown_instructions_have_no_count() {
+    assert_eq!(messages("Pushh 1"), ["unknown instruction `Pushh`"]);
+}
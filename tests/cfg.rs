@@ -0,0 +1,84 @@
+use std::process::Command;
+
+use tinyvm::{CallGraph, Cfg};
+
+mod common;
+use common::{assemble, test_file};
+
+// Runs `vm cfg` on one of the examples, returning what it printed
+fn vm_cfg(args: &[&str], name: &str) -> String {
+    let path = format!("{}/test_files/{}", env!("CARGO_MANIFEST_DIR"), name);
+    let output = Command::new(env!("CARGO_BIN_EXE_vm"))
+        .arg("cfg")
+        .args(args)
+        .arg(&path)
+        .output()
+        .expect("could not run the vm binary");
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn recursion_splits_into_blocks() {
+    let program = assemble(&test_file("recursion.bytecode"));
+    let cfg = Cfg::new(&program.instructions);
+
+    // b1 to b3 are the factorial procedure, as in cfg.rs
+    assert_eq!(
+        cfg.to_string(),
+        "\
+b0    0..1       -> b6 (jump)
+b1    1..2       -> b2, b4 (taken)
+b2    2..6       -> b3, b1 (call)
+b3    6..8       -> exit
+b4    8..11      -> exit
+b5    11..12     -> b6
+b6    12..14     -> b7, b1 (call)
+b7    14..18     -> exit
+"
+    );
+    assert_eq!(cfg.block_at(4), Some(2));
+}
+
+#[test]
+fn the_call_graph_has_an_edge_per_caller() {
+    let program = assemble(&test_file("recursion.bytecode"));
+    let edges: Vec<(String, String)> = CallGraph::new(&program).calls.into_iter().collect();
+    assert_eq!(
+        edges,
+        [
+            ("factorial".to_string(), "factorial".to_string()),
+            ("main".to_string(), "factorial".to_string())
+        ]
+    );
+}
+
+#[test]
+fn dot_output_groups_blocks_by_procedure() {
+    let dot = vm_cfg(&["--dot"], "recursion.bytecode");
+    assert!(dot.starts_with("digraph cfg {\n"), "{}", dot);
+    assert!(dot.ends_with("}\n"), "{}", dot);
+    for line in &[
+        "    subgraph \"cluster_factorial\" {",
+        "        b1 [label=\"factorial:\\l   1  JE 8\\l\"];",
+        "    subgraph \"cluster_main\" {",
+        "    b1 -> b4 [label=\"taken\"];",
+        "    b6 -> b1 [label=\"call\", style=dashed];",
+        "    b0 -> b6 [label=\"jump\"];",
+        "    b6 -> b7;",
+    ] {
+        assert!(dot.lines().any(|l| l == *line), "{}\n{}", line, dot);
+    }
+
+    let calls = vm_cfg(&["--calls", "--dot"], "recursion.bytecode");
+    assert_eq!(
+        calls,
+        "digraph calls {
+    \"factorial\";
+    \"main\";
+    \"factorial\" -> \"factorial\";
+    \"main\" -> \"factorial\";
+}
+"
+    );
+}
@@ -0,0 +1,28 @@
+// Helpers shared by the integration tests. Every file in tests/ is
+// compiled on its own, and most of them only use some of these.
+#![allow(dead_code)]
+
+use tinyvm::{Assembler, Program, Vm};
+
+// assemble assembles the source of a test, which should always work
+pub fn assemble(source: &str) -> Program {
+    Assembler::new("test.bytecode")
+        .assemble(source)
+        .expect("test program should assemble")
+}
+
+pub fn vm(source: &str) -> Vm {
+    Vm::new(assemble(source))
+}
+
+// test_file reads the source of one of the examples in test_files/
+pub fn test_file(name: &str) -> String {
+    let path = format!("{}/test_files/{}", env!("CARGO_MANIFEST_DIR"), name);
+    std::fs::read_to_string(path).unwrap()
+}
+
+// A path in the temp directory which no other test uses
+pub fn temp_path(name: &str) -> String {
+    let path = std::env::temp_dir().join(format!("tinyvm-{}-{}", std::process::id(), name));
+    path.to_str().unwrap().to_string()
+}
@@ -0,0 +1,112 @@
+use tinyvm::{Capture, Debugger};
+
+mod common;
+use common::{test_file, vm};
+
+// Runs a debugging session on recursion.bytecode, returning what it
+// printed before the first prompt and then in answer to each command
+fn session(commands: &[&str]) -> Vec<String> {
+    let source = test_file("recursion.bytecode");
+    let mut vm = vm(&source);
+    vm.set_output(Box::new(Capture::new()));
+
+    let mut debugger = Debugger::new(vm, Some(source));
+    let mut out = Vec::new();
+    let input = commands
+        .iter()
+        .map(|c| format!("{}\n", c))
+        .collect::<String>();
+    debugger.run(input.as_bytes(), &mut out).unwrap();
+
+    String::from_utf8(out)
+        .unwrap()
+        .split("(tvdb) ")
+        .map(str::to_string)
+        .collect()
+}
+
+#[test]
+fn step_runs_one_instruction() {
+    assert_eq!(
+        session(&["s", "step", "s"]),
+        [
+            "ip 0, line 1: Proc factorial\n",
+            "ip 12, line 20: Push 10\n",
+            "ip 13, line 21: Call factorial\n",
+            "ip 1, line 2, in factorial: JE retOne\n",
+            "",
+        ]
+    );
+}
+
+#[test]
+fn next_runs_a_whole_call() {
+    let out = session(&["s", "s", "n", "p"]);
+    assert_eq!(out[3], "ip 14, line 23: Get 0\n");
+    assert_eq!(out[4], "[3628800]\n");
+}
+
+#[test]
+fn finish_runs_until_the_procedure_returns() {
+    let out = session(&["f", "s", "s", "s", "f", "p"]);
+    assert_eq!(out[1], "not inside of a procedure\n");
+    assert_eq!(out[5], "ip 14, line 23: Get 0\n");
+    assert_eq!(out[6], "[3628800]\n");
+}
+
+#[test]
+fn continue_runs_to_the_end() {
+    let out = session(&["c", "s"]);
+    assert_eq!(out[1], "program finished, stack: [3628800, 3628800, 10]\n");
+    assert_eq!(out[2], out[1]);
+}
+
+#[test]
+fn breakpoints_can_be_on_procedures_labels_and_lines() {
+    let out = session(&[
+        "b factorial",
+        "c",
+        "d factorial",
+        "b retOne",
+        "c",
+        "b 24",
+        "c",
+    ]);
+    assert_eq!(out[1], "breakpoint set at ip 1, line 2, in factorial\n");
+    assert_eq!(
+        out[2],
+        "breakpoint hit\nip 1, line 2, in factorial: JE retOne\n"
+    );
+    assert_eq!(out[3], "breakpoint removed at ip 1, line 2, in factorial\n");
+    assert_eq!(out[4], "breakpoint set at ip 8, line 14, in factorial\n");
+    assert_eq!(
+        out[5],
+        "breakpoint hit\nip 8, line 14, in factorial: label retOne\n"
+    );
+    assert_eq!(out[6], "breakpoint set at ip 15, line 24\n");
+    assert_eq!(out[7], "breakpoint hit\nip 15, line 24: Print\n");
+
+    let out = session(&["b nowhere", "d 24"]);
+    assert_eq!(out[1], "no label, procedure or line `nowhere`\n");
+    assert_eq!(out[2], "no breakpoint at `24`\n");
+}
+
+#[test]
+fn the_stack_is_split_at_the_frame() {
+    let out = session(&["p", "s", "s", "s", "p", "s", "s", "p"]);
+    assert_eq!(out[1], "[]\n");
+    assert_eq!(out[5], "[10 |]\n");
+    assert_eq!(out[8], "[10 | 10]\n");
+}
+
+#[test]
+fn the_backtrace_shows_every_call() {
+    let out = session(&["b retOne", "c", "bt"]);
+
+    let mut expected = "#0 ip 8, line 14, in factorial\n".to_string();
+    for i in 1..=10 {
+        expected += &format!("#{} ip 5, line 9, in factorial\n", i);
+    }
+    expected += "#11 ip 13, line 21\n";
+    assert_eq!(out[3], expected);
+}
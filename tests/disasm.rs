@@ -0,0 +1,126 @@
+use tinyvm::{disassemble, object, Assembler, Program};
+
+mod common;
+use common::{assemble, test_file};
+
+// Disassembles a program and assembles it again, checking that nothing
+// the Vm runs has changed
+fn round_trip(program: &Program) -> String {
+    let source = disassemble(program).expect("program should disassemble");
+    let again = Assembler::new("disasm.bytecode")
+        .assemble(&source)
+        .unwrap_or_else(|errors| panic!("{}\n{}", source, errors[0]));
+
+    assert_eq!(again.instructions, program.instructions, "\n{}", source);
+    assert_eq!(again.data, program.data);
+    assert_eq!(again.natives, program.natives);
+    source
+}
+
+#[test]
+fn every_test_file_round_trips() {
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/test_files");
+    let mut files = 0;
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let name = entry.unwrap().file_name().into_string().unwrap();
+        if !name.ends_with(".bytecode") {
+            continue;
+        }
+
+        let program = assemble(&test_file(&name));
+        let disassembled = round_trip(&program);
+
+        // With the same symbols, the names come back too
+        let again = assemble(&disassembled);
+        assert_eq!(again.labels, program.labels, "{}", name);
+        assert_eq!(again.procedures, program.procedures);
+        assert_eq!(again.signatures, program.signatures);
+        assert_eq!(again.constants, program.constants);
+        files += 1;
+    }
+    assert!(files > 0);
+}
+
+#[test]
+fn objects_without_symbols_get_made_up_names() {
+    let source = "
+.native sqrt 1 -> 1
+.data
+    greeting \"hi\\n\"
+.code
+Proc square 1 -> 1
+    GetArg 0
+    GetArg 0
+    Mul
+    Ret 1
+End
+Proc unused
+    Ret
+End
+Push 3
+label loop
+    Push 4
+    Call square
+    Call sqrt
+    Pop
+    Decr
+    JNE loop
+PushAddr greeting
+PrintS
+";
+    let mut program = assemble(source);
+    program.labels.clear();
+    program.procedures.clear();
+    program.signatures.clear();
+    program.constants.clear();
+    let program = object::read(&object::write(&program)).unwrap();
+
+    let disassembled = round_trip(&program);
+    assert!(
+        disassembled.contains("Proc P0 1 -> 1\n"),
+        "{}",
+        disassembled
+    );
+    assert!(disassembled.contains("Proc P6\n"), "{}", disassembled);
+    assert!(disassembled.contains("label L10\n"), "{}", disassembled);
+    assert!(disassembled.contains("JNE L10\n"), "{}", disassembled);
+    assert!(disassembled.contains("Call P0\n"), "{}", disassembled);
+    assert!(
+        disassembled.contains("Push \"hi\\n\"\n"),
+        "{}",
+        disassembled
+    );
+}
+
+#[test]
+fn unused_data_keeps_its_place() {
+    let source = "
+.data
+    a \"x\"
+    b \"yy\"
+.code
+PushAddr b
+PrintS
+";
+    let mut program = assemble(source);
+    program.constants.clear();
+
+    // b is only pushed once, after a, so it can go back to being a literal
+    let disassembled = round_trip(&program);
+    assert_eq!(
+        disassembled,
+        ".data\n    D0 \"x\"\n.code\nPush \"yy\"\nPrintS\n"
+    );
+
+    // Pushed twice, it has to be a constant to point at the same block
+    let mut program = assemble(&format!("{}PushAddr b\nPrintS\n", source));
+    program.constants.clear();
+
+    let disassembled = round_trip(&program);
+    assert!(
+        disassembled.starts_with(".data\n    D0 \"x\"\n    D1 \"yy\"\n.code\n"),
+        "{}",
+        disassembled
+    );
+    assert!(disassembled.contains("PushAddr D1\n"), "{}", disassembled);
+}
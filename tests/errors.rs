@@ -0,0 +1,75 @@
+use tinyvm::{HeapError, VmErrorKind};
+
+mod common;
+use common::vm;
+
+// Runs a program which is expected to fault, returning the kind of
+// fault and where it happened
+fn fault(source: &str) -> (VmErrorKind, usize) {
+    let err = vm(source).run().expect_err("test program should fault");
+    (err.kind, err.ip)
+}
+
+#[test]
+fn popping_an_empty_stack_underflows() {
+    assert_eq!(fault("Pop"), (VmErrorKind::StackUnderflow, 0));
+    assert_eq!(fault("Push 1\nAdd"), (VmErrorKind::StackUnderflow, 1));
+}
+
+#[test]
+fn reaching_outside_the_frame_is_an_error() {
+    assert_eq!(fault("Push 1\nGet 1"), (VmErrorKind::OutOfFrame, 1));
+    assert_eq!(fault("Push 1\nSet 3"), (VmErrorKind::OutOfFrame, 1));
+}
+
+#[test]
+fn returning_from_the_top_level_is_an_error() {
+    assert_eq!(fault("Push 1\nRet"), (VmErrorKind::ReturnWithoutFrame, 1));
+}
+
+#[test]
+fn arithmetic_faults() {
+    assert_eq!(
+        fault("Push 1\nPush 0\nDiv"),
+        (VmErrorKind::DivisionByZero, 2)
+    );
+    assert_eq!(
+        fault("Push 9223372036854775807\nIncr"),
+        (VmErrorKind::Overflow, 1)
+    );
+}
+
+#[test]
+fn values_have_to_have_the_right_type() {
+    assert_eq!(
+        fault("Push true\nPush 1\nAdd"),
+        (
+            VmErrorKind::TypeError {
+                expected: "int or float",
+                found: "bool"
+            },
+            2
+        )
+    );
+}
+
+#[test]
+fn heap_faults_say_what_went_wrong() {
+    assert_eq!(
+        fault("Push -1\nAlloc"),
+        (VmErrorKind::Heap(HeapError::InvalidSize(-1)), 1)
+    );
+    assert_eq!(
+        fault("Push 2\nAlloc\nGet 0\nFree\nFree"),
+        (VmErrorKind::Heap(HeapError::InvalidPointer(1)), 4)
+    );
+}
+
+#[test]
+fn faults_say_where_they_happened() {
+    let err = vm("Push 1\nPush 0\nDiv").run().unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "runtime error: division by zero at instruction 2 (Div)"
+    );
+}
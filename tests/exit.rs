@@ -0,0 +1,110 @@
+use std::process::Command;
+
+use tinyvm::{Capture, VmErrorKind, MAX_EXIT_STATUS};
+
+mod common;
+use common::{temp_path, vm};
+
+// Runs the vm binary on a program, returning the status it exits with
+fn vm_status(name: &str, source: &str) -> Option<i32> {
+    let path = temp_path(name);
+    std::fs::write(&path, source).unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_vm"))
+        .arg("run")
+        .arg(&path)
+        .output()
+        .expect("could not run the vm binary")
+        .status;
+    std::fs::remove_file(&path).unwrap();
+    status.code()
+}
+
+#[test]
+fn halt_stops_the_program() {
+    let mut vm = vm("Push 1\nPrint\nPush 3\nHalt\nPush 2\nPrint");
+    let output = Capture::new();
+    vm.set_output(Box::new(output.clone()));
+    vm.run().unwrap();
+
+    assert_eq!(output.contents(), "1");
+    assert_eq!(vm.exit_status(), Some(3));
+    assert!(vm.is_finished());
+
+    // Unlike Exit, Halt leaves its status on the stack
+    assert_eq!(vm.stack(), [tinyvm::Value::Int(1), tinyvm::Value::Int(3)]);
+}
+
+#[test]
+fn exit_pops_its_status() {
+    let mut vm = vm("Push 7\nPush 42\nExit\nPush 1");
+    vm.run().unwrap();
+
+    assert_eq!(vm.exit_status(), Some(42));
+    assert_eq!(vm.stack(), [tinyvm::Value::Int(7)]);
+}
+
+#[test]
+fn running_off_the_end_has_no_status() {
+    let mut vm = vm("Push 1");
+    vm.run().unwrap();
+    assert_eq!(vm.exit_status(), None);
+}
+
+#[test]
+fn exit_statuses_have_to_be_in_range() {
+    for &status in &[-1, 64, 256, 4294967296] {
+        let err = vm(&format!("Push {}\nExit", status)).run().unwrap_err();
+        assert_eq!(err.kind, VmErrorKind::InvalidStatus(status));
+    }
+
+    let err = vm("Halt").run().unwrap_err();
+    assert_eq!(err.kind, VmErrorKind::StackUnderflow);
+
+    let mut vm = vm(&format!("Push {}\nExit", MAX_EXIT_STATUS));
+    vm.run().unwrap();
+    assert_eq!(vm.exit_status(), Some(MAX_EXIT_STATUS));
+}
+
+#[test]
+fn the_cli_exits_with_the_program_status() {
+    assert_eq!(vm_status("exit.bytecode", "Push 5\nExit"), Some(5));
+    assert_eq!(
+        vm_status("halt.bytecode", "Push 4\nHalt\nPop\nPop"),
+        Some(4)
+    );
+}
+
+#[test]
+fn the_cli_tells_errors_apart() {
+    assert_eq!(vm_status("asm.bytecode", "Jump nowhere"), Some(65));
+    assert_eq!(
+        vm_status("div.bytecode", "Push 1\nPush 0\nDiv"),
+        Some(VmErrorKind::DivisionByZero.exit_code())
+    );
+    assert_eq!(
+        vm_status("pop.bytecode", "Pop"),
+        Some(VmErrorKind::StackUnderflow.exit_code())
+    );
+    assert_eq!(
+        vm_status("status.bytecode", "Push 256\nExit"),
+        Some(VmErrorKind::InvalidStatus(256).exit_code())
+    );
+    assert_ne!(
+        VmErrorKind::DivisionByZero.exit_code(),
+        VmErrorKind::StackUnderflow.exit_code()
+    );
+}
+
+#[test]
+fn error_statuses_dont_collide_with_program_statuses() {
+    let kinds = [
+        VmErrorKind::StackUnderflow,
+        VmErrorKind::OutOfFrame,
+        VmErrorKind::InvalidStatus(-1),
+        VmErrorKind::InvalidByte(256),
+    ];
+    for kind in &kinds {
+        let code = kind.exit_code();
+        assert!(code > MAX_EXIT_STATUS && code != 101 && code <= 255);
+    }
+}
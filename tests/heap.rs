@@ -0,0 +1,16 @@
+use tinyvm::{HeapError, VmErrorKind};
+
+mod common;
+use common::vm;
+
+#[test]
+fn huge_allocations_are_errors() {
+    for n in &["4611686018427387903", "9223372036854775807"] {
+        let err = vm(&format!("Push {}\nAlloc", n)).run().unwrap_err();
+        assert_eq!(err.ip, 1);
+        assert_eq!(
+            err.kind,
+            VmErrorKind::Heap(HeapError::InvalidSize(n.parse().unwrap()))
+        );
+    }
+}
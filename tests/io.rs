@@ -0,0 +1,133 @@
+use std::io::{BufReader, BufWriter, Cursor, Read};
+use std::sync::{Arc, Mutex};
+
+use tinyvm::{Capture, Vm, VmErrorKind};
+
+mod common;
+use common::{test_file, vm};
+
+#[test]
+fn output_can_be_captured() {
+    let mut vm = vm("Push 42\nPrint\nPush 10\nPrintC\nPush \"hi\"\nPrintS");
+    let output = Capture::new();
+    vm.set_output(Box::new(output.clone()));
+    vm.run().unwrap();
+
+    assert_eq!(output.contents(), "42\nhi");
+}
+
+#[test]
+fn print_c_writes_bytes() {
+    let mut vm = vm("Push 195\nPrintC\nPush 169\nPrintC");
+    let output = Capture::new();
+    vm.set_output(Box::new(output.clone()));
+    vm.run().unwrap();
+
+    // The two bytes of é in UTF-8
+    assert_eq!(output.bytes(), [195, 169]);
+}
+
+#[test]
+fn print_c_rejects_ints_which_arent_bytes() {
+    for &c in &[-1, 256, 1065] {
+        let err = vm(&format!("Push {}\nPrintC", c)).run().unwrap_err();
+        assert_eq!(err.kind, VmErrorKind::InvalidByte(c));
+    }
+}
+
+#[test]
+fn print_stack_goes_to_the_diagnostics() {
+    let mut vm = vm("Push 1\nPush 2\nPrintStack\nPrint");
+    let (output, diagnostics) = (Capture::new(), Capture::new());
+    vm.set_output(Box::new(output.clone()));
+    vm.set_diagnostics(Box::new(diagnostics.clone()));
+    vm.run().unwrap();
+
+    assert_eq!(output.contents(), "2");
+    assert_eq!(diagnostics.contents(), "[1, 2]\n");
+}
+
+// Runs a program with the given input, returning what it printed
+fn run_with_input(vm: &mut Vm, input: &'static str) -> String {
+    let output = Capture::new();
+    vm.set_input(Box::new(Cursor::new(input)));
+    vm.set_output(Box::new(output.clone()));
+    vm.run().unwrap();
+    output.contents()
+}
+
+#[test]
+fn read_int_reads_until_the_end_of_the_input() {
+    let source = "
+Push 0
+label loop
+    ReadInt
+    JE done
+    Pop
+    Add
+    Jump loop
+label done
+    Pop
+    Print
+";
+    assert_eq!(run_with_input(&mut vm(source), "1 2\n  30\t-4\n"), "29");
+}
+
+#[test]
+fn read_int_rejects_anything_else() {
+    let mut vm = vm("ReadInt");
+    vm.set_input(Box::new(Cursor::new("12three")));
+    assert_eq!(vm.run().unwrap_err().kind, VmErrorKind::InvalidInput);
+}
+
+#[test]
+fn read_c_pushes_minus_one_at_the_end() {
+    let mut vm = vm("ReadC\nReadC\nReadC\nPrintStack");
+    let diagnostics = Capture::new();
+    vm.set_input(Box::new(Cursor::new("a")));
+    vm.set_diagnostics(Box::new(diagnostics.clone()));
+    vm.run().unwrap();
+
+    assert_eq!(diagnostics.contents(), "[97, -1, -1]\n");
+}
+
+#[test]
+fn wc_counts_lines_words_and_bytes() {
+    let source = test_file("wc.bytecode");
+    let input = "hello world\n  foo\tbar baz\n\nlast";
+    assert_eq!(run_with_input(&mut vm(&source), input), "3 6 31\n");
+}
+
+// An input which remembers what had been printed when it was first read
+struct Answer {
+    output: Capture,
+    seen: Arc<Mutex<Option<String>>>,
+    input: Cursor<&'static str>,
+}
+
+impl Read for Answer {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut seen = self.seen.lock().unwrap();
+        seen.get_or_insert_with(|| self.output.contents());
+        self.input.read(buf)
+    }
+}
+
+#[test]
+fn prompts_are_flushed_before_reading() {
+    for read in &["ReadInt", "ReadC"] {
+        let source = format!("Push \"name? \"\nPrintS\n{}", read);
+        let mut vm = vm(&source);
+        let output = Capture::new();
+        let seen = Arc::new(Mutex::new(None));
+        vm.set_output(Box::new(BufWriter::new(output.clone())));
+        vm.set_input(Box::new(BufReader::new(Answer {
+            output: output.clone(),
+            seen: seen.clone(),
+            input: Cursor::new("7\n"),
+        })));
+        vm.run().unwrap();
+
+        assert_eq!(seen.lock().unwrap().as_deref(), Some("name? "));
+    }
+}
@@ -0,0 +1,95 @@
+use std::time::Duration;
+
+use tinyvm::{Limit, Vm, VmConfig, VmError, VmErrorKind};
+
+mod common;
+use common::{assemble, vm};
+
+// Runs a program which is expected to go over a limit in config
+fn limited(source: &str, config: VmConfig) -> (Vm, VmError) {
+    let mut vm = Vm::with_config(assemble(source), config);
+    let err = vm.run().expect_err("test program should go over its limit");
+    (vm, err)
+}
+
+#[test]
+fn the_stack_limit_stops_the_push_past_it() {
+    let config = VmConfig {
+        max_stack: Some(2),
+        ..VmConfig::default()
+    };
+    let (_, err) = limited("Push 1\nPush 2\nPush 3\nPush 4", config);
+
+    assert_eq!(err.kind, VmErrorKind::LimitExceeded(Limit::Stack(2)));
+    assert_eq!(err.ip, 2);
+}
+
+#[test]
+fn the_call_limit_stops_the_call_past_it() {
+    let config = VmConfig {
+        max_calls: Some(3),
+        ..VmConfig::default()
+    };
+    let (vm, err) = limited("Proc f\n    Call f\nEnd\nCall f", config);
+
+    assert_eq!(err.kind, VmErrorKind::LimitExceeded(Limit::Calls(3)));
+    assert_eq!(err.ip, 1);
+    assert_eq!(vm.call_stack().len(), 3);
+}
+
+#[test]
+fn the_instruction_limit_stops_before_the_next_instruction() {
+    let config = VmConfig {
+        max_instructions: Some(5),
+        ..VmConfig::default()
+    };
+    let (vm, err) = limited("label loop\nJump loop", config);
+
+    assert_eq!(err.kind, VmErrorKind::LimitExceeded(Limit::Instructions(5)));
+    assert_eq!(err.ip, 1);
+    assert_eq!(vm.executed(), 5);
+}
+
+#[test]
+fn the_timeout_stops_a_program_which_runs_forever() {
+    let timeout = Duration::from_millis(10);
+    let config = VmConfig {
+        timeout: Some(timeout),
+        ..VmConfig::default()
+    };
+    let (vm, err) = limited("label loop\nJump loop", config);
+
+    assert_eq!(err.kind, VmErrorKind::LimitExceeded(Limit::Time(timeout)));
+    assert!(err.ip < 2);
+    assert_eq!(err.ip, vm.pointer());
+}
+
+#[test]
+fn the_heap_limit_stops_the_alloc_past_it() {
+    let config = VmConfig {
+        max_heap: Some(10),
+        ..VmConfig::default()
+    };
+    let (vm, err) = limited("Push 8\nAlloc\nPush 4\nAlloc", config);
+
+    assert_eq!(err.kind, VmErrorKind::LimitExceeded(Limit::Heap(10)));
+    assert_eq!(err.ip, 3);
+    assert_eq!(vm.heap().allocated(), 8);
+}
+
+#[test]
+fn enter_fails_without_enough_memory() {
+    let err = vm("Push 1\nEnter 18446744073709551615").run().unwrap_err();
+    assert_eq!(err.ip, 1);
+    assert_eq!(err.kind, VmErrorKind::TooManyLocals(usize::MAX));
+}
+
+#[test]
+fn enter_checks_the_stack_limit_first() {
+    let config = VmConfig {
+        max_stack: Some(16),
+        ..VmConfig::default()
+    };
+    let (_, err) = limited("Enter 18446744073709551615", config);
+    assert_eq!(err.kind, VmErrorKind::LimitExceeded(Limit::Stack(16)));
+}
@@ -0,0 +1,82 @@
+use tinyvm::{Capture, Value};
+
+mod common;
+use common::{test_file, vm};
+
+// Runs a program to the end, returning what's left on its stack
+fn stack(source: &str) -> Vec<Value> {
+    let mut vm = vm(source);
+    vm.run().expect("test program should run");
+    vm.stack().to_vec()
+}
+
+#[test]
+fn enter_pushes_zeroed_locals() {
+    let source = "
+Proc f
+    Enter 2
+    Ret 2
+End
+Push 9
+Call f
+";
+    assert_eq!(stack(source), [Value::Int(9), Value::Int(0), Value::Int(0)]);
+}
+
+#[test]
+fn locals_can_be_got_and_set() {
+    let source = "
+Proc f
+    Enter 2
+    Push 5
+    SetPop 1
+    Get 1
+    Incr
+    Ret 1
+End
+Call f
+";
+    assert_eq!(stack(source), [Value::Int(6)]);
+}
+
+#[test]
+fn ret_n_keeps_the_top_of_the_frame() {
+    let source = "
+Proc f
+    Push 1
+    Push 2
+    Push 3
+    Ret 2
+End
+Push 9
+Call f
+";
+    assert_eq!(stack(source), [Value::Int(9), Value::Int(2), Value::Int(3)]);
+}
+
+#[test]
+fn ret_n_drops_the_arguments_of_a_signed_procedure() {
+    let source = "
+Proc f 2 -> 1
+    GetArg 0
+    GetArg 1
+    Push 100
+    Ret 1
+End
+Push 9
+Push 1
+Push 2
+Call f
+";
+    assert_eq!(stack(source), [Value::Int(9), Value::Int(100)]);
+}
+
+#[test]
+fn locals_example_sums_squares() {
+    let mut vm = vm(&test_file("locals.bytecode"));
+    let output = Capture::new();
+    vm.set_output(Box::new(output.clone()));
+    vm.run().unwrap();
+
+    assert_eq!(output.contents(), "385\n");
+}
@@ -0,0 +1,160 @@
+use tinyvm::{verify, Assembler, Capture, Instruction, Value, VmErrorKind};
+
+mod common;
+use common::{assemble, vm};
+
+fn sqrt(args: &[Value]) -> Result<Vec<Value>, String> {
+    match args[0] {
+        Value::Int(n) if n >= 0 => Ok(vec![Value::Int((n as f64).sqrt() as isize)]),
+        v => Err(format!("can't take the square root of {}", v)),
+    }
+}
+
+#[test]
+fn natives_replace_their_arguments_with_their_results() {
+    let mut vm = vm(".native sqrt 1 -> 1\nPush 1\nPush 16\nCall sqrt\nPrint");
+    let output = Capture::new();
+    vm.set_output(Box::new(output.clone()));
+    assert!(vm.register_native("sqrt", sqrt));
+    vm.run().unwrap();
+
+    assert_eq!(output.contents(), "4");
+    assert_eq!(vm.stack(), &[Value::Int(1), Value::Int(4)]);
+    assert_eq!(vm.program().instructions[2], Instruction::CallNative(0));
+}
+
+#[test]
+fn natives_see_their_arguments_like_get_arg() {
+    let mut vm = vm(".native sub 2 -> 1\nPush 10\nPush 3\nCall sub");
+    vm.register_native("sub", |args| match (args[0], args[1]) {
+        (Value::Int(a), Value::Int(b)) => Ok(vec![Value::Int(b - a)]),
+        _ => Err("expected ints".to_string()),
+    });
+    vm.run().unwrap();
+
+    assert_eq!(vm.stack(), &[Value::Int(7)]);
+}
+
+#[test]
+fn procedures_take_priority_over_natives() {
+    let source = "
+.native sqrt 1 -> 1
+Proc sqrt
+    Push 0
+    SetArg 0
+    Pop
+    Ret
+End
+Push 16
+Call sqrt
+";
+    let mut vm = vm(source);
+    vm.register_native("sqrt", sqrt);
+    vm.run().unwrap();
+
+    assert_eq!(vm.program().natives.len(), 1);
+    assert!(!vm
+        .program()
+        .instructions
+        .contains(&Instruction::CallNative(0)));
+    assert_eq!(vm.stack(), &[Value::Int(0)]);
+}
+
+#[test]
+fn natives_have_to_be_registered() {
+    let mut vm = vm(".native sqrt 1 -> 1\nPush 16\nCall sqrt");
+    assert_eq!(vm.unregistered_natives(), ["sqrt"]);
+    let err = vm.run().unwrap_err();
+    assert_eq!(err.kind, VmErrorKind::UnknownNative("sqrt".to_string()));
+}
+
+#[test]
+fn only_declared_natives_can_be_registered() {
+    let mut vm = vm(".native sqrt 1 -> 1\nPush 16\nCall sqrt");
+    assert!(!vm.register_native("sqrtt", sqrt));
+    assert_eq!(vm.unregistered_natives(), ["sqrt"]);
+}
+
+#[test]
+fn natives_need_enough_arguments() {
+    let mut vm = vm(".native hypot 2 -> 1\nPush 16\nCall hypot");
+    vm.register_native("hypot", |_| Ok(vec![]));
+    let err = vm.run().unwrap_err();
+
+    assert_eq!(
+        err.kind,
+        VmErrorKind::NativeArity {
+            name: "hypot".to_string(),
+            expected: 2,
+            found: 1,
+        }
+    );
+}
+
+#[test]
+fn failing_natives_stop_the_program() {
+    let mut vm = vm(".native sqrt 1 -> 1\nPush -1\nCall sqrt\nPrint");
+    vm.register_native("sqrt", sqrt);
+    let err = vm.run().unwrap_err();
+
+    assert_eq!(err.ip, 1);
+    assert_eq!(
+        err.to_string(),
+        "runtime error: `sqrt` failed: can't take the square root of -1 at instruction 1 (CallNative(0))"
+    );
+}
+
+#[test]
+fn natives_have_to_return_what_they_declare() {
+    let mut vm = vm(".native sqrt 1 -> 1\nPush 16\nCall sqrt");
+    vm.register_native("sqrt", |_| Ok(vec![]));
+    let err = vm.run().unwrap_err();
+
+    assert_eq!(
+        err.kind,
+        VmErrorKind::WrongResultCount {
+            expected: 1,
+            found: 0
+        }
+    );
+}
+
+#[test]
+fn natives_have_to_be_declared() {
+    let errors = Assembler::new("test.bytecode")
+        .assemble("Push 16\nCall sqrtt")
+        .unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].message, "unknown procedure `sqrtt`");
+
+    let program = Assembler::new("test.bytecode")
+        .native("sqrt", 1, 1)
+        .assemble("Push 16\nCall sqrt")
+        .unwrap();
+    assert_eq!(program.instructions[1], Instruction::CallNative(0));
+}
+
+#[test]
+fn declarations_have_to_agree() {
+    let errors = Assembler::new("test.bytecode")
+        .native("sqrt", 1, 1)
+        .assemble(".native sqrt 2 -> 1\n.native pow\nPush 1")
+        .unwrap_err();
+    let messages: Vec<&str> = errors.iter().map(|e| e.message.as_str()).collect();
+    assert_eq!(
+        messages,
+        [
+            "native `sqrt` is already declared differently",
+            "expected a native like `.native sqrt 1 -> 1`",
+        ]
+    );
+}
+
+#[test]
+fn the_verifier_checks_past_natives() {
+    let program = assemble(".native sqrt 1 -> 1\nPush 16\nCall sqrt\nPop\nPop");
+    let errors = verify(&program).unwrap_err();
+
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].ip, 3);
+}
@@ -0,0 +1,43 @@
+use tinyvm::program::Signature;
+use tinyvm::{object, DecodeError};
+
+mod common;
+use common::assemble;
+
+#[test]
+fn objects_round_trip() {
+    let program = assemble("Proc double 1 -> 1\n    GetArg 0\n    Push 2\n    Mul\n    Ret 1\nEnd\nPush 4\nCall double\nPrint");
+    let mut read = object::read(&object::write(&program)).unwrap();
+
+    // Spans aren't kept in object files
+    read.spans = program.spans.clone();
+    assert_eq!(read, program);
+}
+
+#[test]
+fn procedures_have_to_be_inside_the_code() {
+    let len = assemble("Push 1").instructions.len();
+    for &(start, end) in &[(0, 0), (1, 0), (0, len + 1)] {
+        let mut program = assemble("Push 1");
+        program.procedures.insert("p".to_string(), (start, end));
+
+        let err = object::read(&object::write(&program)).unwrap_err();
+        assert!(
+            matches!(err, DecodeError::InvalidProcedure { .. }),
+            "{:?}",
+            err
+        );
+    }
+}
+
+#[test]
+fn signatures_have_to_fit_in_an_isize() {
+    let mut program = assemble("Push 1");
+    let args = isize::MAX as usize + 1;
+    program
+        .signatures
+        .insert("p".to_string(), Signature { args, results: 0 });
+
+    let err = object::read(&object::write(&program)).unwrap_err();
+    assert!(matches!(err, DecodeError::Overflow { .. }), "{:?}", err);
+}
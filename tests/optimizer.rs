@@ -0,0 +1,146 @@
+use std::process::Command;
+
+use tinyvm::{optimize, Instruction, Value};
+
+mod common;
+use common::assemble;
+
+// Runs an example through the vm binary, returning what it printed to
+// stdout and to stderr, where PrintStack goes.
+fn run(file: &str, opt_level: &str) -> (String, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_vm"))
+        .args(["run", opt_level, file])
+        .output()
+        .expect("could not run the vm binary");
+    assert!(output.status.success(), "{} failed at {}", file, opt_level);
+    (
+        String::from_utf8(output.stdout).unwrap(),
+        String::from_utf8(output.stderr).unwrap(),
+    )
+}
+
+#[test]
+fn examples_print_the_same_at_every_level() {
+    // fib_recurse and ackermann take too long in debug builds
+    let examples = [
+        "cat",
+        "data",
+        "echo",
+        "fib",
+        "heap",
+        "hello_world",
+        "locals",
+        "procedure",
+        "recursion",
+        "sum",
+        "wc",
+    ];
+
+    for example in examples.iter() {
+        let file = format!(
+            "{}/test_files/{}.bytecode",
+            env!("CARGO_MANIFEST_DIR"),
+            example
+        );
+        assert_eq!(run(&file, "-O0"), run(&file, "-O1"), "{}", example);
+    }
+}
+
+#[test]
+fn push_one_add_becomes_incr() {
+    let mut program = assemble("Push 5\nGet 0\nPush 1\nAdd\nPush 1\nSub\nPrint");
+    optimize(&mut program);
+
+    use Instruction::*;
+    assert_eq!(
+        program.instructions,
+        vec![Push(Value::Int(5)), Get(0), Incr, Decr, Print]
+    );
+}
+
+#[test]
+fn constants_are_folded() {
+    let mut program = assemble("Push 6\nPush 4\nSub\nPush 3\nMul\nIncr\nPrint");
+    optimize(&mut program);
+
+    use Instruction::*;
+    assert_eq!(program.instructions, vec![Push(Value::Int(7)), Print]);
+}
+
+#[test]
+fn faulting_constants_are_not_folded() {
+    let mut program = assemble("Push 1\nPush 0\nDiv\nPush 1\nPush 2.5\nAdd");
+    let before = program.instructions.clone();
+    optimize(&mut program);
+    assert_eq!(program.instructions, before);
+}
+
+#[test]
+fn noops_are_removed_and_jumps_fixed_up() {
+    let source = "
+Push 3
+label loop
+    Decr
+    JNE loop
+Print
+";
+    let mut program = assemble(source);
+    optimize(&mut program);
+
+    use Instruction::*;
+    assert_eq!(
+        program.instructions,
+        vec![Push(Value::Int(3)), Decr, JNE(1), Print]
+    );
+    assert_eq!(program.labels["loop"], 1);
+    assert_eq!(program.spans.len(), program.instructions.len());
+}
+
+#[test]
+fn jump_chains_are_threaded() {
+    let source = "
+Jump a
+label c
+    Print
+label a
+    Jump b
+label b
+    Jump c
+";
+    let mut program = assemble(source);
+    optimize(&mut program);
+
+    use Instruction::*;
+    assert_eq!(program.instructions, vec![Jump(1), Print, Jump(1), Jump(1)]);
+}
+
+#[test]
+fn set_pop_is_fused() {
+    let mut program = assemble("Push 0\nPush 2\nSet 0\nPop\nPrint");
+    optimize(&mut program);
+
+    use Instruction::*;
+    assert_eq!(
+        program.instructions,
+        vec![Push(Value::Int(0)), Push(Value::Int(2)), SetPop(0), Print]
+    );
+}
+
+#[test]
+fn patterns_with_a_jump_into_them_are_kept() {
+    let source = "
+Push 2
+Push 1
+label inside
+    Add
+    JNE inside
+";
+    let mut program = assemble(source);
+    optimize(&mut program);
+
+    use Instruction::*;
+    assert_eq!(
+        program.instructions,
+        vec![Push(Value::Int(2)), Push(Value::Int(1)), Add, JNE(2)]
+    );
+}
@@ -0,0 +1,85 @@
+use tinyvm::{Capture, Profiler, Program};
+
+mod common;
+use common::{test_file, vm};
+
+// Profiles recursion.bytecode, which calls factorial 11 times deep
+fn profile() -> (Profiler, Program) {
+    let mut vm = vm(&test_file("recursion.bytecode"));
+    vm.set_output(Box::new(Capture::new()));
+    let mut profiler = Profiler::new(vm.program());
+    profiler.run(&mut vm).unwrap();
+    (profiler, vm.program().clone())
+}
+
+fn report() -> Vec<String> {
+    let (profiler, program) = profile();
+    let mut out = Vec::new();
+    profiler.write_report(&program, &mut out).unwrap();
+    String::from_utf8(out)
+        .unwrap()
+        .lines()
+        .map(str::to_string)
+        .collect()
+}
+
+#[test]
+fn opcodes_are_counted() {
+    let report = report();
+    assert_eq!(report[0], "profile: 81 instructions executed");
+    for row in &[
+        "Push                   13  16.05%",
+        "Call                   11  13.58%",
+        "Ret                    11  13.58%",
+        "Mul                    10  12.35%",
+        "Print                   1   1.23%",
+    ] {
+        assert!(report.contains(&row.to_string()), "{}", row);
+    }
+}
+
+#[test]
+fn instructions_are_counted() {
+    let report = report();
+    for row in &[
+        "     1      2           11  13.58%  JE 8",
+        "     5      9           10  12.35%  Call 1",
+        "     8     14            1   1.23%  Noop",
+    ] {
+        assert!(report.contains(&row.to_string()), "{}", row);
+    }
+}
+
+#[test]
+fn recursion_is_only_counted_once_inclusively() {
+    let report = report();
+    // 11 calls which run 74 instructions between them, rather than
+    // the 74 + 67 + 60 + ... it would be if every call counted them
+    assert!(report.contains(
+        &"factorial                11           74  91.36%           74  91.36%".to_string()
+    ));
+    assert!(report.contains(
+        &"main                      1           81 100.00%            7   8.64%".to_string()
+    ));
+}
+
+#[test]
+fn folded_stacks_follow_the_recursion() {
+    let (profiler, _) = profile();
+    let mut out = Vec::new();
+    profiler.write_folded(&mut out).unwrap();
+
+    let mut expected = String::new();
+    for depth in 0..=11 {
+        let chain = vec!["factorial"; depth];
+        let count = if depth == 11 { 4 } else { 7 };
+        expected += &format!(
+            "main{}{} {}\n",
+            ";".repeat(depth.min(1)),
+            chain.join(";"),
+            count
+        );
+    }
+    assert_eq!(String::from_utf8(out).unwrap(), expected);
+    assert_eq!(profiler.total(), 81);
+}
@@ -0,0 +1,69 @@
+use tinyvm::{Capture, RunStatus, Value, VmErrorKind};
+
+mod common;
+use common::{test_file, vm};
+
+#[test]
+fn run_for_yields_when_the_budget_runs_out() {
+    let mut vm = vm("Push 1\nPush 2\nAdd\nPrint");
+    let output = Capture::new();
+    vm.set_output(Box::new(output.clone()));
+
+    assert!(matches!(vm.run_for(2), RunStatus::Yielded));
+    assert_eq!(vm.stack(), &[Value::Int(1), Value::Int(2)]);
+    assert_eq!(vm.pointer(), 2);
+    assert_eq!(output.contents(), "");
+
+    // Resuming carries on from the same instruction
+    assert!(matches!(vm.run_for(1), RunStatus::Yielded));
+    assert_eq!(vm.stack(), &[Value::Int(3)]);
+
+    assert!(matches!(vm.run_for(10), RunStatus::Halted));
+    assert_eq!(output.contents(), "3");
+    assert_eq!(vm.executed(), 4);
+}
+
+#[test]
+fn run_for_gives_the_same_result_in_slices() {
+    let source = test_file("recursion.bytecode");
+
+    let whole = Capture::new();
+    let mut a = vm(&source);
+    a.set_output(Box::new(whole.clone()));
+    a.run().unwrap();
+
+    let sliced = Capture::new();
+    let mut b = vm(&source);
+    b.set_output(Box::new(sliced.clone()));
+    let mut slices = 1;
+    while let RunStatus::Yielded = b.run_for(3) {
+        slices += 1;
+    }
+    b.flush().unwrap();
+
+    assert!(slices > 1);
+    assert_eq!(sliced.contents(), whole.contents());
+    assert_eq!(b.stack(), a.stack());
+}
+
+#[test]
+fn run_for_halts_at_the_end() {
+    let mut vm = vm("Push 1");
+    assert!(matches!(vm.run_for(10), RunStatus::Halted));
+
+    // There's nothing left to run, so it stays halted
+    assert!(matches!(vm.run_for(10), RunStatus::Halted));
+    assert_eq!(vm.executed(), 1);
+}
+
+#[test]
+fn run_for_returns_faults() {
+    let mut vm = vm("Push 1\nPush 0\nDiv\nPrint");
+    match vm.run_for(10) {
+        RunStatus::Error(e) => {
+            assert_eq!(e.kind, VmErrorKind::DivisionByZero);
+            assert_eq!(e.ip, 2);
+        }
+        status => panic!("expected a fault, got {:?}", status),
+    }
+}
@@ -0,0 +1,80 @@
+use tinyvm::{Assembler, Value, VmErrorKind};
+
+mod common;
+use common::vm;
+
+// Assembles a program which is expected not to, returning its one error
+fn error(source: &str) -> String {
+    let errors = Assembler::new("test.bytecode")
+        .assemble(source)
+        .expect_err("test program shouldn't assemble");
+    assert_eq!(errors.len(), 1, "{:?}", errors);
+    errors[0].message.clone()
+}
+
+#[test]
+fn signed_procedures_drop_their_arguments() {
+    let mut vm = vm("
+Proc add 2 -> 1
+    GetArg 0
+    GetArg 1
+    Add
+    Ret
+End
+Push 7
+Push 2
+Push 3
+Call add
+");
+    vm.run().unwrap();
+    assert_eq!(vm.stack(), [Value::Int(7), Value::Int(5)]);
+}
+
+#[test]
+fn returning_the_wrong_number_of_results_faults() {
+    let mut vm = vm("
+Proc pair 1 -> 2
+    GetArg 0
+    Ret
+End
+Push 1
+Call pair
+");
+    let err = vm.run().unwrap_err();
+    assert_eq!(
+        err.kind,
+        VmErrorKind::WrongResultCount {
+            expected: 2,
+            found: 1
+        }
+    );
+    // The Return that compiled from the Ret
+    assert_eq!(err.ip, 2);
+}
+
+#[test]
+fn arguments_past_the_signature_dont_assemble() {
+    for op in &["GetArg", "SetArg"] {
+        let source = format!("Proc f 1 -> 0\n    {} 1\n    Ret\nEnd", op);
+        assert_eq!(
+            error(&source),
+            format!("`f` only takes 1 argument, so `{} 1` is out of range", op)
+        );
+    }
+}
+
+#[test]
+fn ret_n_has_to_match_the_signature() {
+    assert_eq!(
+        error("Proc f 1 -> 1\n    GetArg 0\n    Ret 2\nEnd"),
+        "`f` returns 1 value, so it can't `Ret 2`"
+    );
+}
+
+#[test]
+fn tail_calls_dont_assemble_in_signed_procedures() {
+    assert_eq!(
+        error("Proc f 1 -> 0\n    TailCall f\nEnd"),
+        "`TailCall` can't be used in `f`, since it has a signature"
+    );
+}
@@ -0,0 +1,85 @@
+use tinyvm::{snapshot, Capture, Limit, Program, SnapshotError, Vm, VmConfig, VmErrorKind};
+
+mod common;
+use common::{assemble, test_file};
+
+fn example(name: &str) -> Program {
+    assemble(&test_file(name))
+}
+
+// Runs a program to the end, returning what it printed
+fn run(vm: &mut Vm) -> String {
+    let output = Capture::new();
+    vm.set_output(Box::new(output.clone()));
+    vm.run().unwrap();
+    output.contents()
+}
+
+#[test]
+fn snapshots_carry_on_where_they_stopped() {
+    for name in &["heap.bytecode", "recursion.bytecode"] {
+        let expected = run(&mut Vm::new(example(name)));
+
+        for &cut in &[1, 20, 50] {
+            let config = VmConfig {
+                max_instructions: Some(cut),
+                ..VmConfig::default()
+            };
+            let mut vm = Vm::with_config(example(name), config);
+            let before = Capture::new();
+            vm.set_output(Box::new(before.clone()));
+            let err = vm.run().unwrap_err();
+            assert_eq!(
+                err.kind,
+                VmErrorKind::LimitExceeded(Limit::Instructions(cut))
+            );
+
+            let bytes = snapshot::write(&vm);
+            let mut resumed = snapshot::read(example(name), VmConfig::default(), &bytes).unwrap();
+            assert_eq!(resumed.stack(), vm.stack());
+            assert_eq!(resumed.heap(), vm.heap());
+
+            let after = run(&mut resumed);
+            assert_eq!(
+                before.contents() + &after,
+                expected,
+                "{} cut at {}",
+                name,
+                cut
+            );
+        }
+    }
+}
+
+#[test]
+fn snapshots_only_resume_the_same_program() {
+    let config = VmConfig {
+        max_instructions: Some(10),
+        ..VmConfig::default()
+    };
+    let mut vm = Vm::with_config(example("heap.bytecode"), config);
+    vm.run().unwrap_err();
+    let bytes = snapshot::write(&vm);
+
+    let other = example("recursion.bytecode");
+    let err = match snapshot::read(other.clone(), VmConfig::default(), &bytes) {
+        Ok(_) => panic!("resumed a snapshot of another program"),
+        Err(e) => e,
+    };
+    assert_eq!(
+        err,
+        SnapshotError::WrongProgram {
+            expected: snapshot::program_hash(&other),
+            found: snapshot::program_hash(vm.program()),
+        }
+    );
+}
+
+#[test]
+fn snapshots_have_to_be_snapshots() {
+    let program = example("heap.bytecode");
+    match snapshot::read(program, VmConfig::default(), b"TVMC") {
+        Ok(_) => panic!("resumed an object file"),
+        Err(e) => assert_eq!(e.to_string(), "not a tinyvm snapshot"),
+    }
+}
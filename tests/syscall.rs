@@ -0,0 +1,156 @@
+use std::process::Command;
+
+use tinyvm::{Assembler, Capabilities, Capability, Capture, Value, Vm, VmConfig, VmErrorKind};
+
+mod common;
+use common::{assemble, temp_path};
+
+fn vm(source: &str, capabilities: Capabilities) -> Vm {
+    let config = VmConfig {
+        capabilities,
+        ..VmConfig::default()
+    };
+    Vm::with_config(assemble(source), config)
+}
+
+#[test]
+fn programs_can_read_their_arguments() {
+    let source = "
+Syscall 6
+Print
+Push 1
+Syscall 7
+Pop
+PrintS
+Push 5
+Syscall 7
+";
+    let mut vm = vm(source, Capabilities::SANDBOXED);
+    let output = Capture::new();
+    vm.set_output(Box::new(output.clone()));
+    vm.set_args(vec!["test.bytecode".to_string(), "hello".to_string()]);
+    vm.run().unwrap();
+
+    assert_eq!(output.contents(), "2hello");
+    assert_eq!(vm.stack()[2..], [Value::Int(0), Value::Bool(false)]);
+}
+
+#[test]
+fn files_can_be_written_and_read_back() {
+    let path = temp_path("files");
+    let source = format!(
+        r#"
+Push "{path}"
+Push 1
+Syscall 0
+Pop
+-- [fd]
+Get 0
+Push "one\ntwo\n"
+Syscall 2
+Syscall 3
+
+Push "{path}"
+Push 0
+Syscall 0
+Pop
+Get 0
+Syscall 1
+Pop
+PrintS
+Pop
+Get 0
+Syscall 1
+Pop
+PrintS
+Pop
+Syscall 1
+"#,
+        path = path
+    );
+    let mut vm = vm(&source, Capabilities::TRUSTED);
+    let output = Capture::new();
+    vm.set_output(Box::new(output.clone()));
+    vm.run().unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(output.contents(), "one\ntwo\n");
+    // The end of the file
+    assert_eq!(vm.stack(), [Value::Int(0), Value::Bool(false)]);
+}
+
+#[test]
+fn opening_a_missing_file_pushes_false() {
+    let source = format!("Push \"{}\"\nPush 0\nSyscall 0", temp_path("missing"));
+    let mut vm = vm(&source, Capabilities::TRUSTED);
+    vm.run().unwrap();
+
+    assert_eq!(vm.stack(), [Value::Int(0), Value::Bool(false)]);
+}
+
+#[test]
+fn the_sandbox_denies_files() {
+    let source = format!("Push \"{}\"\nPush 1\nSyscall 0", temp_path("denied"));
+    let err = vm(&source, Capabilities::default()).run().unwrap_err();
+
+    assert_eq!(
+        err.kind,
+        VmErrorKind::Denied {
+            syscall: "open",
+            capability: Capability::Files,
+        }
+    );
+    assert!(!std::path::Path::new(&temp_path("denied")).exists());
+}
+
+#[test]
+fn closed_files_cant_be_used() {
+    let err = vm("Push 3\nSyscall 3", Capabilities::TRUSTED)
+        .run()
+        .unwrap_err();
+    assert_eq!(err.kind, VmErrorKind::BadFile(3));
+}
+
+#[test]
+fn exit_stops_the_program() {
+    let mut vm = vm("Push 3\nSyscall 5\nPush 1\nPrint", Capabilities::SANDBOXED);
+    let output = Capture::new();
+    vm.set_output(Box::new(output.clone()));
+    vm.run().unwrap();
+
+    assert_eq!(vm.exit_status(), Some(3));
+    assert!(vm.is_finished());
+    assert_eq!(output.contents(), "");
+}
+
+#[test]
+fn the_clock_goes_forward() {
+    let mut vm = vm("Syscall 4\nSyscall 4", Capabilities::SANDBOXED);
+    vm.run().unwrap();
+
+    match vm.stack() {
+        [Value::Int(before), Value::Int(after)] => assert!(after >= before),
+        stack => panic!("unexpected stack {:?}", stack),
+    }
+}
+
+#[test]
+fn unknown_syscalls_dont_assemble() {
+    let errors = Assembler::new("test.bytecode")
+        .assemble("Syscall 99")
+        .unwrap_err();
+    assert_eq!(errors[0].message, "there's no syscall 99");
+}
+
+#[test]
+fn the_cli_passes_arguments_and_exits_with_the_status() {
+    let path = temp_path("cli.bytecode");
+    std::fs::write(&path, "Syscall 6\nSyscall 5").unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_vm"))
+        .args(["run", &path, "a", "--b", "c"])
+        .status()
+        .expect("could not run the vm binary");
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(status.code(), Some(4));
+}
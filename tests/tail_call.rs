@@ -0,0 +1,57 @@
+use tinyvm::{optimize, Instruction, Program, Vm};
+
+mod common;
+use common::assemble;
+
+// Counts n down to zero, one call per step
+const COUNTDOWN: &str = "
+Proc countdown
+    Decr
+    JE done
+    Call countdown
+    Ret
+
+    label done
+        Ret
+End
+
+Push 10000000
+Call countdown
+";
+
+// Runs a program to the end, returning the deepest the call stack and
+// the stack got along the way.
+fn run(program: Program) -> (usize, usize) {
+    let mut vm = Vm::new(program);
+    let (mut calls, mut values) = (0, 0);
+    while vm.step().expect("test program should run") {
+        calls = calls.max(vm.call_stack().len());
+        values = values.max(vm.stack().len());
+    }
+    (calls, values)
+}
+
+#[test]
+fn call_ret_becomes_tail_call() {
+    let mut program = assemble(COUNTDOWN);
+    optimize(&mut program);
+
+    let start = program.procedures["countdown"].0;
+    assert!(program
+        .instructions
+        .contains(&Instruction::TailCall(start + 1)));
+}
+
+#[test]
+fn deep_tail_recursion_runs_in_one_frame() {
+    let mut program = assemble(COUNTDOWN);
+    optimize(&mut program);
+
+    assert_eq!(run(program), (1, 1));
+}
+
+#[test]
+fn tail_call_can_be_written_by_hand() {
+    let source = COUNTDOWN.replace("Call countdown\n    Ret", "TailCall countdown");
+    assert_eq!(run(assemble(&source)), (1, 1));
+}
@@ -0,0 +1,68 @@
+use tinyvm::{TraceFormat, Tracer, VmErrorKind};
+
+mod common;
+use common::vm;
+
+// Calls a procedure and then divides by zero, so that the trace goes
+// into a stack frame, back out, and faults
+const SOURCE: &str = "Proc f\n    GetArg 0\n    Ret\nEnd\nPush 5\nCall f\nPush 0\nDiv";
+
+// Traces a program to its end, returning the trace and whether the
+// program faulted
+fn trace(source: &str, format: TraceFormat) -> (String, Option<VmErrorKind>) {
+    let mut vm = vm(source);
+    let mut out = Vec::new();
+    let res = Tracer::new(&mut out, format).run(&mut vm).unwrap();
+    (String::from_utf8(out).unwrap(), res.err().map(|e| e.kind))
+}
+
+#[test]
+fn text_traces_split_the_stack_at_the_frame() {
+    let (out, err) = trace(SOURCE, TraceFormat::Text);
+    assert_eq!(
+        out,
+        "   ip  line  proc          instruction       stack
+    0     1  -             Jump 4            [] -> []
+    4     5  -             Push 5            [] -> [5]
+    5     6  -             Call 1            [5] -> [5 |]
+    1     2  f             GetArg 0          [5 |] -> [5 | 5]
+    2     3  f             Ret               [5 | 5] -> [5, 5]
+    6     7  -             Push 0            [5, 5] -> [5, 5, 0]
+    7     8  -             Div               [5, 5, 0] -> error: division by zero
+"
+    );
+    assert_eq!(err, Some(VmErrorKind::DivisionByZero));
+}
+
+#[test]
+fn json_traces_give_the_frame_offset() {
+    let (out, err) = trace(SOURCE, TraceFormat::Json);
+    let lines: Vec<&str> = out.lines().collect();
+    assert_eq!(lines.len(), 7);
+    assert_eq!(
+        lines[0],
+        r#"{"ip":0,"line":1,"proc":null,"op":"Jump 4","frame":0,"before":[],"after":[]}"#
+    );
+    assert_eq!(
+        lines[3],
+        r#"{"ip":1,"line":2,"proc":"f","op":"GetArg 0","frame":1,"before":[5],"after":[5,5]}"#
+    );
+    assert_eq!(
+        lines[6],
+        r#"{"ip":7,"line":8,"proc":null,"op":"Div","frame":0,"before":[5,5,0],"error":"division by zero"}"#
+    );
+    assert_eq!(err, Some(VmErrorKind::DivisionByZero));
+}
+
+#[test]
+fn json_values_keep_their_types() {
+    let (out, _) = trace("Push \"hi\"\nPush 1.5\nPush true", TraceFormat::Json);
+    assert!(
+        out.lines()
+            .last()
+            .unwrap()
+            .ends_with(r#""after":[{"str":1},1.5,true]}"#),
+        "{}",
+        out
+    );
+}
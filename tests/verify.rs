@@ -0,0 +1,144 @@
+use tinyvm::{verify, Span, VerifyError};
+
+mod common;
+use common::{assemble, test_file};
+
+// Verifies a program, returning each error's line and message
+fn errors(source: &str) -> Vec<(usize, String)> {
+    verify(&assemble(source))
+        .err()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|e| (e.span.unwrap().line, e.message))
+        .collect()
+}
+
+#[test]
+fn balanced_programs_verify() {
+    for name in &["recursion.bytecode", "ackermann.bytecode", "heap.bytecode"] {
+        assert_eq!(errors(&test_file(name)), [], "{}", name);
+    }
+}
+
+#[test]
+fn underflows_are_errors() {
+    assert_eq!(
+        errors("Push 1\nAdd\nPrint"),
+        [(
+            2,
+            "stack underflow: `Add` needs 2 values but the stack has 1".to_string()
+        )]
+    );
+}
+
+#[test]
+fn paths_have_to_join_at_the_same_height() {
+    let source = "
+Push 1
+Push 0
+JE skip
+Push 2
+label skip
+Print
+";
+    assert_eq!(
+        errors(source),
+        [(
+            6,
+            "the stack is 1 deep here on one path but 3 on another".to_string()
+        )]
+    );
+}
+
+#[test]
+fn procedures_have_to_match_their_signatures() {
+    let source = "
+Proc pair 1 -> 2
+    GetArg 0
+    Ret
+End
+Push 1
+Call pair
+";
+    assert_eq!(
+        errors(source),
+        [(
+            4,
+            "`pair` returns 1 value here, but its signature says 2".to_string()
+        )]
+    );
+}
+
+#[test]
+fn errors_carry_on_past_the_first() {
+    let errors = errors("Pop\nGet 3\nRet");
+    let lines: Vec<usize> = errors.iter().map(|e| e.0).collect();
+    assert_eq!(lines, [1, 2, 3]);
+    assert_eq!(errors[2].1, "`Ret` is outside of a procedure");
+}
+
+#[test]
+fn errors_point_at_their_instruction() {
+    let errors = verify(&assemble("Push 1\n  Mul")).unwrap_err();
+    assert_eq!(
+        errors,
+        [VerifyError {
+            ip: 1,
+            span: Some(Span {
+                line: 2,
+                column: 3,
+                len: 3
+            }),
+            message: "stack underflow: `Mul` needs 2 values but the stack has 1".to_string(),
+        }]
+    );
+}
+
+#[test]
+fn enter_has_to_fit_on_the_stack() {
+    for n in &["9223372036854775807", "18446744073709551615"] {
+        let source = format!("Push 1\nEnter {}", n);
+        assert_eq!(
+            errors(&source),
+            [(
+                2,
+                format!("`Enter {}` asks for more locals than the stack can hold", n)
+            )]
+        );
+    }
+}
+
+#[test]
+fn indices_have_to_be_inside_the_frame() {
+    for i in &["1", "18446744073709551615"] {
+        let source = format!("Push 1\nGet {}\nPrint", i);
+        assert_eq!(
+            errors(&source),
+            [(
+                2,
+                format!(
+                    "`Get {}` is outside of the stack frame, which holds 1 value",
+                    i
+                )
+            )]
+        );
+    }
+}
+
+#[test]
+fn calls_have_to_fit_on_the_stack() {
+    let source = "
+Proc f 0 -> 9223372036854775807
+    Ret
+End
+Push 1
+Call f
+";
+    assert_eq!(
+        errors(source)[1],
+        (
+            6,
+            "`Call 1` leaves more values than the stack can hold".to_string()
+        )
+    );
+}